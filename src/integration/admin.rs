@@ -1,13 +1,84 @@
 use crate::types::api::ErrorWrapper;
 use crate::types::auth::{AuthTokens, SSORes, UserClaims};
 use crate::types::masternode::{AssignMasternodeRes, MasternodeInfo};
-use anyhow::anyhow;
-use anyhow::Error;
-use anyhow::Result;
 use async_trait::async_trait;
 use mockall::automock;
 use reqwest::{Client, StatusCode};
 use std::{fmt::Debug, sync::Arc};
+use thiserror::Error;
+
+pub type Result<T, E = AdminError> = std::result::Result<T, E>;
+
+/// errors of `AdminService`, `op` names the admin endpoint that failed
+#[derive(Debug, Error)]
+pub enum AdminError {
+    #[error("admin unauthorized token")]
+    Unauthorized,
+    #[error("admin refused to refresh token")]
+    RefreshRejected,
+    #[error("no masternode is online")]
+    NoMasternodeOnline,
+    /// the admin service could not be reached
+    #[error("admin {op} request failed err={source}")]
+    Request {
+        op: &'static str,
+        #[source]
+        source: reqwest::Error,
+    },
+    #[error("admin {op} failed status={status} err={err_msg:?}")]
+    UpstreamStatus {
+        op: &'static str,
+        status: StatusCode,
+        err_msg: Option<String>,
+    },
+    #[error("admin {op} decode json failed err={source}")]
+    Decode {
+        op: &'static str,
+        #[source]
+        source: reqwest::Error,
+    },
+}
+
+impl AdminError {
+    fn request(op: &'static str) -> impl FnOnce(reqwest::Error) -> Self {
+        move |source| Self::Request { op, source }
+    }
+
+    fn decode(op: &'static str) -> impl FnOnce(reqwest::Error) -> Self {
+        move |source| Self::Decode { op, source }
+    }
+
+    /// builds the error of an unexpected status, the body is kept when admin sent an `ErrorWrapper`
+    async fn upstream(op: &'static str, res: reqwest::Response) -> Self {
+        let status = res.status();
+        let err_msg = res.json::<ErrorWrapper>().await.ok().map(|e| e.err_msg());
+        Self::UpstreamStatus {
+            op,
+            status,
+            err_msg,
+        }
+    }
+}
+
+impl From<&AdminError> for ErrorWrapper {
+    fn from(e: &AdminError) -> Self {
+        match e {
+            AdminError::Unauthorized | AdminError::RefreshRejected => {
+                ErrorWrapper::builder(StatusCode::UNAUTHORIZED, &e.to_string())
+            }
+            AdminError::NoMasternodeOnline => {
+                ErrorWrapper::builder(StatusCode::SERVICE_UNAVAILABLE, &e.to_string())
+            }
+            // the request error carries the admin url, it is not leaked to clients
+            AdminError::Request { .. } => {
+                ErrorWrapper::builder(StatusCode::SERVICE_UNAVAILABLE, "admin unavailable")
+            }
+            AdminError::UpstreamStatus { .. } | AdminError::Decode { .. } => {
+                ErrorWrapper::builder(StatusCode::BAD_GATEWAY, &e.to_string())
+            }
+        }
+    }
+}
 
 #[automock]
 #[async_trait]
@@ -38,7 +109,7 @@ pub struct AdminServiceImpl {
 }
 
 impl AdminServiceImpl {
-    pub async fn new(base_url: String) -> Result<Self, Error> {
+    pub async fn new(base_url: String) -> anyhow::Result<Self> {
         let base_url: String = match base_url.strip_suffix('/') {
             Some(url) => url.to_string(),
             None => base_url.clone(),
@@ -101,109 +172,86 @@ impl AdminServiceImpl {
 
 #[async_trait]
 impl AdminService for AdminServiceImpl {
-    async fn health_check(self: Arc<Self>) -> Result<(), Error> {
+    async fn health_check(self: Arc<Self>) -> Result<()> {
+        let op = "health check";
         let client = reqwest::Client::new();
-        let admin_health_response = client.clone().get(self.health_check.clone()).send().await;
-
-        match admin_health_response {
-            Ok(res) => match res.status() {
-                StatusCode::OK => Ok(()),
-                code => {
-                    return Err(anyhow!(
-                        "health check failed err=unknonw status code {}",
-                        code
-                    ))
-                }
-            },
-            Err(err) => Err(anyhow!("health check failed err={}", err)),
+        let res = client
+            .get(self.health_check.clone())
+            .send()
+            .await
+            .map_err(AdminError::request(op))?;
+
+        match res.status() {
+            StatusCode::OK => Ok(()),
+            _ => Err(AdminError::upstream(op, res).await),
         }
     }
 
-    async fn verify_auth_token(
-        self: Arc<Self>,
-        auth_tokens: AuthTokens,
-    ) -> Result<UserClaims, Error> {
+    async fn verify_auth_token(self: Arc<Self>, auth_tokens: AuthTokens) -> Result<UserClaims> {
+        let op = "verify auth token";
         let client = reqwest::Client::new();
-        match client
+        let res = client
             .post(self.verify_auth_token.clone())
             .json(&auth_tokens)
             .send()
             .await
-        {
-            Ok(res) => match res.status() {
-                StatusCode::OK => match res.json::<UserClaims>().await {
-                    Ok(claims) => Ok(claims),
-                    Err(e) => return Err(anyhow!("decode user claims failed err={}", e)),
-                },
-                StatusCode::UNAUTHORIZED => {
-                    return Err(anyhow!("unauthorized token={:?}", auth_tokens))
-                }
-                unknown => {
-                    return Err(anyhow!(
-                        "verify auth token failed unknown status code={}",
-                        unknown
-                    ))
-                }
-            },
-            Err(e) => {
-                return Err(anyhow!(
-                    "verify auth token failed http request failed err={}",
-                    e
-                ))
-            }
+            .map_err(AdminError::request(op))?;
+
+        match res.status() {
+            StatusCode::OK => res
+                .json::<UserClaims>()
+                .await
+                .map_err(AdminError::decode(op)),
+            StatusCode::UNAUTHORIZED => Err(AdminError::Unauthorized),
+            _ => Err(AdminError::upstream(op, res).await),
         }
     }
 
     async fn refresh_token(self: Arc<Self>, auth_tokens: AuthTokens) -> Result<AuthTokens> {
+        let op = "refresh token";
         let client = reqwest::Client::new();
-        match client
+        let res = client
             .post(self.refresh_token_path.clone())
             .json(&auth_tokens)
             .send()
             .await
-        {
-            Ok(res) => match res.status() {
-                StatusCode::OK => match res.json::<SSORes>().await {
-                    Ok(sso_res) => match sso_res.code {
-                        1 => {
-                            let auth_tokens = AuthTokens {
-                                access_token: sso_res.access_token.unwrap(),
-                                refresh_token: sso_res.refresh_token.unwrap(),
-                            };
-                            Ok(auth_tokens.clone())
-                        }
-                        _ => return Err(anyhow!("failed to refresh token")),
-                    },
-                    Err(_) => return Err(anyhow!("decode json failed")),
-                },
-                _ => return Err(anyhow!("get assign masternode failed")),
-            },
-            Err(_) => return Err(anyhow!("get assign masternode failed")),
+            .map_err(AdminError::request(op))?;
+
+        if res.status() != StatusCode::OK {
+            return Err(AdminError::upstream(op, res).await);
+        }
+        let sso_res = res.json::<SSORes>().await.map_err(AdminError::decode(op))?;
+        match (sso_res.code, sso_res.access_token, sso_res.refresh_token) {
+            (1, Some(access_token), Some(refresh_token)) => Ok(AuthTokens {
+                access_token,
+                refresh_token,
+            }),
+            _ => Err(AdminError::RefreshRejected),
         }
     }
 
     async fn assign_masternode(self: Arc<Self>, auth_tokens: AuthTokens) -> Result<MasternodeInfo> {
+        let op = "assign masternode";
         let _self = self.clone();
         let client = self.get_authorized_client(auth_tokens.access_token);
-        match client
+        let res = client
             .get(_self.assign_masternode_path.clone())
             .send()
             .await
-        {
-            Ok(res) => match res.status() {
-                StatusCode::OK => match res.json::<AssignMasternodeRes>().await {
-                    Ok(assign_masternode_rs) => match assign_masternode_rs.masternode {
-                        Some(masternode) => return Ok(masternode),
-                        None => return Err(anyhow!("no masternode is online")),
-                    },
-                    Err(_) => return Err(anyhow!("decode masternode json failed")),
-                },
-                StatusCode::UNAUTHORIZED => {
-                    return Err(anyhow!("invalid access token"));
-                }
-                _ => return Err(anyhow!("unknown status code")),
-            },
-            Err(_) => return Err(anyhow!("cannot send request to admin service",)),
+            .map_err(AdminError::request(op))?;
+
+        match res.status() {
+            StatusCode::OK => {
+                let assign_masternode_rs = res
+                    .json::<AssignMasternodeRes>()
+                    .await
+                    .map_err(AdminError::decode(op))?;
+                assign_masternode_rs
+                    .masternode
+                    .ok_or(AdminError::NoMasternodeOnline)
+            }
+            StatusCode::UNAUTHORIZED => Err(AdminError::Unauthorized),
+            _ => Err(AdminError::upstream(op, res).await),
         }
     }
 
@@ -211,45 +259,34 @@ impl AdminService for AdminServiceImpl {
         self: Arc<Self>,
         x_api_key: String,
         info: MasternodeInfo,
-    ) -> Result<(), Error> {
+    ) -> Result<()> {
+        let op = "register masternode";
         let client = self.clone().get_xapikey_client(x_api_key);
-        match client
-            .clone()
+        let res = client
             .post(self.register_masternode.clone())
             .json(&info)
             .send()
             .await
-        {
-            Ok(res) => match res.status() {
-                StatusCode::OK => return Ok(()),
-                _ => {
-                    return Err(anyhow!(
-                        "register masternode failed err={:?} ",
-                        res.json::<ErrorWrapper>().await
-                    ))
-                }
-            },
-            Err(e) => return Err(anyhow!("register master node failed err={}", e)),
+            .map_err(AdminError::request(op))?;
+
+        match res.status() {
+            StatusCode::OK => Ok(()),
+            _ => Err(AdminError::upstream(op, res).await),
         }
     }
 
-    async fn deregister_masternode(self: Arc<Self>, x_api_key: String) -> Result<(), Error> {
+    async fn deregister_masternode(self: Arc<Self>, x_api_key: String) -> Result<()> {
+        let op = "deregister masternode";
         let client = self.clone().get_xapikey_client(x_api_key);
-        match client
-            .clone()
+        let res = client
             .post(self.deregister_masternode.clone())
             .send()
             .await
-        {
-            Ok(res) => match res.status() {
-                StatusCode::OK => return Ok(()),
-                _ => {
-                    return Err(anyhow!(
-                        "deregister master node failed err=unknown status code"
-                    ))
-                }
-            },
-            Err(e) => return Err(anyhow!("deregister master node failed err={}", e)),
+            .map_err(AdminError::request(op))?;
+
+        match res.status() {
+            StatusCode::OK => Ok(()),
+            _ => Err(AdminError::upstream(op, res).await),
         }
     }
 }
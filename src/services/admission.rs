@@ -0,0 +1,199 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::types::geo::{AnonymousIp, ConnectionType, Network};
+
+use super::{error::GeoError, geo::GeoService, types::PeerChangedInfo};
+
+/// why a peer ip looks like it is not a residential connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AdmissionReason {
+    Anonymous,
+    AnonymousVpn,
+    HostingProvider,
+    PublicProxy,
+    ResidentialProxy,
+    TorExitNode,
+    /// the connection type database reports a corporate or datacenter network
+    CorporateNetwork,
+}
+
+/// ordered from the mildest to the strictest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AdmissionAction {
+    Allow,
+    /// the peer is admitted but should be watched
+    Flag,
+    Deny,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdmissionVerdict {
+    Allow,
+    Flag(Vec<AdmissionReason>),
+    Deny(Vec<AdmissionReason>),
+}
+
+impl AdmissionVerdict {
+    pub fn is_denied(&self) -> bool {
+        matches!(self, Self::Deny(_))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AdmissionPolicy {
+    /// action taken for each reason, a reason missing here is allowed
+    pub actions: HashMap<AdmissionReason, AdmissionAction>,
+}
+
+impl Default for AdmissionPolicy {
+    /// denies vpns, tor, public proxies and hosting providers, flags the rest
+    fn default() -> Self {
+        Self {
+            actions: HashMap::from([
+                (AdmissionReason::Anonymous, AdmissionAction::Flag),
+                (AdmissionReason::AnonymousVpn, AdmissionAction::Deny),
+                (AdmissionReason::HostingProvider, AdmissionAction::Deny),
+                (AdmissionReason::PublicProxy, AdmissionAction::Deny),
+                (AdmissionReason::ResidentialProxy, AdmissionAction::Flag),
+                (AdmissionReason::TorExitNode, AdmissionAction::Deny),
+                (AdmissionReason::CorporateNetwork, AdmissionAction::Flag),
+            ]),
+        }
+    }
+}
+
+impl AdmissionPolicy {
+    pub fn action(&self, reason: AdmissionReason) -> AdmissionAction {
+        self.actions
+            .get(&reason)
+            .copied()
+            .unwrap_or(AdmissionAction::Allow)
+    }
+
+    /// the strictest action of the reasons, together with every reason not allowed
+    pub fn verdict(&self, reasons: Vec<AdmissionReason>) -> AdmissionVerdict {
+        let reasons: Vec<AdmissionReason> = reasons
+            .into_iter()
+            .filter(|r| self.action(*r) != AdmissionAction::Allow)
+            .collect();
+        let action = reasons
+            .iter()
+            .map(|r| self.action(*r))
+            .max()
+            .unwrap_or(AdmissionAction::Allow);
+        match action {
+            AdmissionAction::Allow => AdmissionVerdict::Allow,
+            AdmissionAction::Flag => AdmissionVerdict::Flag(reasons),
+            AdmissionAction::Deny => AdmissionVerdict::Deny(reasons),
+        }
+    }
+}
+
+/// checks peers against the anonymous ip and connection type databases of `GeoService`
+/// so vpn, tor and cloud hosted peers can be kept out of the network
+///
+/// a masternode calls `check_peer` before publishing `PeerChanged::Connected`,
+/// a denied peer is disconnected instead. without the databases every peer is allowed
+#[derive(Debug)]
+pub struct PeerAdmission {
+    geo_service: Arc<GeoService>,
+    policy: AdmissionPolicy,
+}
+
+impl PeerAdmission {
+    pub fn new(geo_service: Arc<GeoService>, policy: AdmissionPolicy) -> Self {
+        Self {
+            geo_service,
+            policy,
+        }
+    }
+
+    pub fn check(self: Arc<Self>, ip_addr: String) -> Result<AdmissionVerdict, GeoError> {
+        let anonymous_ip = self
+            .geo_service
+            .clone()
+            .get_anonymous_ip_from_ip_address(ip_addr.clone())?
+            .unwrap_or_default();
+        let network = self
+            .geo_service
+            .clone()
+            .get_network_from_ip_address(ip_addr)?;
+        Ok(self
+            .policy
+            .verdict(admission_reasons(&anonymous_ip, network.as_ref())))
+    }
+
+    pub fn check_peer(
+        self: Arc<Self>,
+        info: &PeerChangedInfo,
+    ) -> Result<AdmissionVerdict, GeoError> {
+        self.check(info.ip.to_string())
+    }
+}
+
+fn admission_reasons(
+    anonymous_ip: &AnonymousIp,
+    network: Option<&Network>,
+) -> Vec<AdmissionReason> {
+    let mut reasons: Vec<AdmissionReason> = vec![];
+    for (flagged, reason) in [
+        (anonymous_ip.is_anonymous, AdmissionReason::Anonymous),
+        (anonymous_ip.is_anonymous_vpn, AdmissionReason::AnonymousVpn),
+        (
+            anonymous_ip.is_hosting_provider,
+            AdmissionReason::HostingProvider,
+        ),
+        (anonymous_ip.is_public_proxy, AdmissionReason::PublicProxy),
+        (
+            anonymous_ip.is_residential_proxy,
+            AdmissionReason::ResidentialProxy,
+        ),
+        (anonymous_ip.is_tor_exit_node, AdmissionReason::TorExitNode),
+    ] {
+        if flagged {
+            reasons.push(reason);
+        }
+    }
+    if network.and_then(|n| n.connection_type) == Some(ConnectionType::Corporate) {
+        reasons.push(AdmissionReason::CorporateNetwork);
+    }
+    reasons
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verdict() {
+        let policy = AdmissionPolicy::default();
+        let residential = Network {
+            connection_type: Some(ConnectionType::CableDsl),
+            ..Default::default()
+        };
+        let reasons = admission_reasons(&AnonymousIp::default(), Some(&residential));
+        assert_eq!(policy.verdict(reasons), AdmissionVerdict::Allow);
+
+        let vpn = AnonymousIp {
+            is_anonymous: true,
+            is_anonymous_vpn: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.verdict(admission_reasons(&vpn, None)),
+            AdmissionVerdict::Deny(vec![
+                AdmissionReason::Anonymous,
+                AdmissionReason::AnonymousVpn
+            ])
+        );
+
+        let mut lenient = AdmissionPolicy::default();
+        lenient
+            .actions
+            .insert(AdmissionReason::AnonymousVpn, AdmissionAction::Allow);
+        assert_eq!(
+            lenient.verdict(admission_reasons(&vpn, None)),
+            AdmissionVerdict::Flag(vec![AdmissionReason::Anonymous])
+        );
+    }
+}
@@ -0,0 +1,374 @@
+use std::{net::AddrParseError, time::Duration};
+
+use maxminddb::MaxMindDBError;
+use redis::{ErrorKind, RedisError};
+use reqwest::StatusCode;
+use thiserror::Error;
+
+use crate::{integration::admin::AdminError, types::api::ErrorWrapper};
+
+use super::lock::STALE_FENCE_ERROR;
+
+/// errors of `RedisService`, a missing key is told apart from redis being down
+#[derive(Debug, Error)]
+pub enum RedisServiceError {
+    #[error("redis key not found key={key}")]
+    NotFound { key: String },
+    #[error("redis failed to decode key={key} err={source}")]
+    Decode {
+        key: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    /// redis could not be reached or dropped the link, the command may be retried
+    #[error("redis connection failed op={op} err={source}")]
+    Connection {
+        op: String,
+        #[source]
+        source: RedisError,
+    },
+    /// redis was reached but refused the command
+    #[error("redis command failed op={op} err={source}")]
+    Command {
+        op: String,
+        #[source]
+        source: RedisError,
+    },
+    #[error("redis invalid argument err={0}")]
+    InvalidArgument(String),
+    /// a later holder of the lock already wrote with a higher fencing token
+    #[error("redis refused stale fencing token op={op} fencing_token={fencing_token}")]
+    StaleFencingToken { op: String, fencing_token: u64 },
+}
+
+impl RedisServiceError {
+    /// wraps an error returned by redis for the operation `op`,
+    /// io errors, timeouts and failovers are connection errors
+    pub fn redis(op: impl Into<String>, source: RedisError) -> Self {
+        let op = op.into();
+        let is_connection = source.is_io_error()
+            || source.is_timeout()
+            || source.is_connection_dropped()
+            || source.is_connection_refusal()
+            || matches!(
+                source.kind(),
+                ErrorKind::ReadOnly | ErrorKind::MasterDown | ErrorKind::TryAgain
+            );
+        match is_connection {
+            true => Self::Connection { op, source },
+            false => Self::Command { op, source },
+        }
+    }
+
+    /// like `redis`, for a script guarded by `fencing_token`, see `LockGuard`
+    pub fn fenced(op: impl Into<String>, fencing_token: Option<u64>, source: RedisError) -> Self {
+        match (fencing_token, source.code() == Some(STALE_FENCE_ERROR)) {
+            (Some(fencing_token), true) => Self::StaleFencingToken {
+                op: op.into(),
+                fencing_token,
+            },
+            _ => Self::redis(op, source),
+        }
+    }
+
+    pub fn decode(key: impl Into<String>, source: serde_json::Error) -> Self {
+        Self::Decode {
+            key: key.into(),
+            source,
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, Self::NotFound { .. })
+    }
+}
+
+/// errors of matching a proxy account to a peer, see `peer_matcher`
+#[derive(Debug, Error)]
+pub enum PeerMatchError {
+    #[error("invalid prioritized ip ip={ip} err={source}")]
+    InvalidPrioritizedIp {
+        ip: String,
+        #[source]
+        source: AddrParseError,
+    },
+    /// the account only accepts its prioritized ip and no peer has it
+    #[error("prioritized ip unavailable ip={ip}")]
+    PrioritizedIpUnavailable { ip: String },
+    #[error("no peer available country_geoname_id={country_geoname_id} city_geoname_id={city_geoname_id:?}")]
+    NoPeerAvailable {
+        country_geoname_id: i64,
+        city_geoname_id: Option<i64>,
+    },
+}
+
+/// errors of `IpRotationScheduler`, an account without a matching peer is
+/// a `PeerMatchError` and told apart from redis failing
+#[derive(Debug, Error)]
+pub enum IpRotationError {
+    #[error(transparent)]
+    Match(#[from] PeerMatchError),
+    #[error(transparent)]
+    Redis(#[from] RedisServiceError),
+    #[error("no peer assigned proxy_acc_id={proxy_acc_id} sticky_key={sticky_key}")]
+    NotAssigned {
+        proxy_acc_id: String,
+        sticky_key: String,
+    },
+    /// the peer was rotated by another masternode after the state was read
+    #[error("peer rotated meanwhile proxy_acc_id={proxy_acc_id} sticky_key={sticky_key}")]
+    Rotated {
+        proxy_acc_id: String,
+        sticky_key: String,
+    },
+}
+
+/// rejections of `ProxyAuthVerifier`
+#[derive(Debug, Error)]
+pub enum ProxyAuthError {
+    #[error("proxy acc not found")]
+    UnknownAccount,
+    #[error("proxy acc wrong password")]
+    WrongPassword,
+    /// too many failed attempts for the username, `retry_after` is left of the block
+    #[error("proxy acc blocked retry_after={retry_after:?}")]
+    Blocked { retry_after: Duration },
+    #[error("parse ip failed ip={ip} err={source}")]
+    InvalidIp {
+        ip: String,
+        #[source]
+        source: AddrParseError,
+    },
+    #[error("ip not whitelisted ip={ip}")]
+    IpNotWhitelisted { ip: String },
+    /// more than one proxy acc whitelists the ip, none of them is picked
+    #[error("ip whitelisted by several proxy accs ip={ip} proxy_acc_ids={proxy_acc_ids:?}")]
+    AmbiguousIp {
+        ip: String,
+        proxy_acc_ids: Vec<String>,
+    },
+    #[error("invalid whitelist whitelist={whitelist} err={reason}")]
+    InvalidWhitelist { whitelist: String, reason: String },
+}
+
+/// errors of `GeoService`
+#[derive(Debug, Error)]
+pub enum GeoError {
+    #[error("parse ip addr failed ip_addr={ip_addr} err={source}")]
+    InvalidIp {
+        ip_addr: String,
+        #[source]
+        source: AddrParseError,
+    },
+    #[error("no mmdb record for ip_addr={ip_addr}")]
+    NotFound { ip_addr: String },
+    #[error("mmdb lookup failed ip_addr={ip_addr} err={source}")]
+    Lookup {
+        ip_addr: String,
+        #[source]
+        source: MaxMindDBError,
+    },
+    #[error("failed to read mmdb file path={path} err={source}")]
+    Open {
+        path: String,
+        #[source]
+        source: MaxMindDBError,
+    },
+    /// the file was read but is not a valid update of the current database
+    #[error("rejected mmdb file path={path} err={reason}")]
+    Rejected { path: String, reason: String },
+}
+
+impl GeoError {
+    /// wraps an error of a mmdb lookup, an address missing from the database is `NotFound`
+    pub fn lookup(ip_addr: impl Into<String>, source: MaxMindDBError) -> Self {
+        let ip_addr = ip_addr.into();
+        match source {
+            MaxMindDBError::AddressNotFoundError(_) => Self::NotFound { ip_addr },
+            source => Self::Lookup { ip_addr, source },
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, Self::NotFound { .. })
+    }
+}
+
+impl From<&RedisServiceError> for ErrorWrapper {
+    fn from(e: &RedisServiceError) -> Self {
+        match e {
+            RedisServiceError::NotFound { .. } => {
+                ErrorWrapper::builder(StatusCode::NOT_FOUND, "not found")
+            }
+            RedisServiceError::InvalidArgument(_) => {
+                ErrorWrapper::builder(StatusCode::BAD_REQUEST, &e.to_string())
+            }
+            RedisServiceError::StaleFencingToken { .. } => {
+                ErrorWrapper::builder(StatusCode::CONFLICT, "lock lost")
+            }
+            // keys and redis internals are not leaked to clients
+            RedisServiceError::Connection { .. } => {
+                ErrorWrapper::builder(StatusCode::SERVICE_UNAVAILABLE, "storage unavailable")
+            }
+            RedisServiceError::Decode { .. } | RedisServiceError::Command { .. } => {
+                ErrorWrapper::builder(StatusCode::INTERNAL_SERVER_ERROR, "storage failed")
+            }
+        }
+    }
+}
+
+impl From<&GeoError> for ErrorWrapper {
+    fn from(e: &GeoError) -> Self {
+        match e {
+            GeoError::InvalidIp { .. } => {
+                ErrorWrapper::builder(StatusCode::BAD_REQUEST, &e.to_string())
+            }
+            GeoError::NotFound { .. } => {
+                ErrorWrapper::builder(StatusCode::NOT_FOUND, &e.to_string())
+            }
+            GeoError::Lookup { .. } | GeoError::Open { .. } | GeoError::Rejected { .. } => {
+                ErrorWrapper::builder(StatusCode::INTERNAL_SERVER_ERROR, "geo lookup failed")
+            }
+        }
+    }
+}
+
+impl From<&PeerMatchError> for ErrorWrapper {
+    fn from(e: &PeerMatchError) -> Self {
+        match e {
+            PeerMatchError::InvalidPrioritizedIp { .. } => {
+                ErrorWrapper::builder(StatusCode::BAD_REQUEST, &e.to_string())
+            }
+            PeerMatchError::PrioritizedIpUnavailable { .. }
+            | PeerMatchError::NoPeerAvailable { .. } => {
+                ErrorWrapper::builder(StatusCode::SERVICE_UNAVAILABLE, &e.to_string())
+            }
+        }
+    }
+}
+
+impl From<&IpRotationError> for ErrorWrapper {
+    fn from(e: &IpRotationError) -> Self {
+        match e {
+            IpRotationError::Match(e) => e.into(),
+            IpRotationError::Redis(e) => e.into(),
+            IpRotationError::NotAssigned { .. } => {
+                ErrorWrapper::builder(StatusCode::NOT_FOUND, "no peer assigned")
+            }
+            IpRotationError::Rotated { .. } => {
+                ErrorWrapper::builder(StatusCode::CONFLICT, "peer rotated")
+            }
+        }
+    }
+}
+
+impl From<&ProxyAuthError> for ErrorWrapper {
+    fn from(e: &ProxyAuthError) -> Self {
+        match e {
+            ProxyAuthError::InvalidIp { .. } => {
+                ErrorWrapper::builder(StatusCode::BAD_REQUEST, &e.to_string())
+            }
+            ProxyAuthError::Blocked { .. } => {
+                ErrorWrapper::builder(StatusCode::TOO_MANY_REQUESTS, "too many attempts")
+            }
+            // which part of the credentials was wrong is not told to clients
+            ProxyAuthError::UnknownAccount
+            | ProxyAuthError::WrongPassword
+            | ProxyAuthError::IpNotWhitelisted { .. }
+            | ProxyAuthError::AmbiguousIp { .. }
+            | ProxyAuthError::InvalidWhitelist { .. } => {
+                ErrorWrapper::builder(StatusCode::UNAUTHORIZED, "proxy auth failed")
+            }
+        }
+    }
+}
+
+/// maps an error returned through `anyhow` to the response of its service error,
+/// errors that are not service errors are internal errors
+pub fn to_error_wrapper(e: &anyhow::Error) -> ErrorWrapper {
+    if let Some(e) = e.downcast_ref::<RedisServiceError>() {
+        return e.into();
+    }
+    if let Some(e) = e.downcast_ref::<GeoError>() {
+        return e.into();
+    }
+    if let Some(e) = e.downcast_ref::<PeerMatchError>() {
+        return e.into();
+    }
+    if let Some(e) = e.downcast_ref::<IpRotationError>() {
+        return e.into();
+    }
+    if let Some(e) = e.downcast_ref::<ProxyAuthError>() {
+        return e.into();
+    }
+    if let Some(e) = e.downcast_ref::<AdminError>() {
+        return e.into();
+    }
+    ErrorWrapper::builder(StatusCode::INTERNAL_SERVER_ERROR, "internal error")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_error_wrapper() {
+        let not_found: anyhow::Error = RedisServiceError::NotFound {
+            key: "peers_ms#a:1".to_owned(),
+        }
+        .into();
+        let wrapper = to_error_wrapper(&not_found);
+        assert_eq!(wrapper.status_code(), 404);
+        assert_eq!(wrapper.err_msg(), "not found");
+
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        let down = RedisServiceError::redis("hget key=a:b", RedisError::from(io));
+        assert!(matches!(down, RedisServiceError::Connection { .. }));
+        assert_eq!(to_error_wrapper(&down.into()).status_code(), 503);
+
+        let invalid_ip = "not an ip".parse::<std::net::IpAddr>().unwrap_err();
+        let geo: anyhow::Error = GeoError::InvalidIp {
+            ip_addr: "not an ip".to_owned(),
+            source: invalid_ip,
+        }
+        .into();
+        assert_eq!(to_error_wrapper(&geo).status_code(), 400);
+
+        let admin: anyhow::Error = AdminError::Unauthorized.into();
+        assert_eq!(to_error_wrapper(&admin).status_code(), 401);
+        let source = reqwest::Client::new()
+            .get("http://admin.internal:bad")
+            .build()
+            .unwrap_err();
+        let admin: anyhow::Error = AdminError::Request {
+            op: "assign masternode",
+            source,
+        }
+        .into();
+        let wrapper = to_error_wrapper(&admin);
+        assert_eq!(wrapper.status_code(), 503);
+        assert_eq!(wrapper.err_msg(), "admin unavailable");
+
+        let stale: anyhow::Error = RedisServiceError::StaleFencingToken {
+            op: "remove all proxy accs".to_owned(),
+            fencing_token: 1,
+        }
+        .into();
+        assert_eq!(
+            to_error_wrapper(&stale).build().status(),
+            StatusCode::CONFLICT
+        );
+        let rotated: anyhow::Error = IpRotationError::Rotated {
+            proxy_acc_id: "acc".to_owned(),
+            sticky_key: "_".to_owned(),
+        }
+        .into();
+        assert_eq!(
+            to_error_wrapper(&rotated).build().status(),
+            StatusCode::CONFLICT
+        );
+
+        let other = anyhow::anyhow!("something else");
+        assert_eq!(to_error_wrapper(&other).status_code(), 500);
+    }
+}
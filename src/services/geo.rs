@@ -1,88 +1,506 @@
-use std::{net::IpAddr, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
 
-use anyhow::{anyhow, Result};
-use maxminddb::{geoip2, Reader};
+use arc_swap::{ArcSwap, Guard};
+use log::{info, warn};
+use maxminddb::{geoip2, Metadata, Reader};
 
-use crate::types::geo::{City, Continent, Country, Geo, Location};
+use crate::types::geo::{
+    localized_name, AnonymousIp, City, ConnectionType, Continent, Country, Geo, Location, Network,
+    Subdivision,
+};
 
+use super::error::GeoError;
+
+/// lookups against the city mmdb file and the optional asn, isp, connection type and
+/// anonymous ip files, every file can be swapped while the service runs
+///
+/// a reload loads the new file next to the current one and swaps it in at once,
+/// lookups in flight keep the reader they started with
 #[derive(Debug)]
 pub struct GeoService {
-    reader: Reader<Vec<u8>>,
+    city: MmdbFile,
+    asn: Option<MmdbFile>,
+    isp: Option<MmdbFile>,
+    connection_type: Option<MmdbFile>,
+    anonymous_ip: Option<MmdbFile>,
 }
 
 impl GeoService {
-    pub fn new(mmdb_path: String) -> Result<Self> {
-        let reader = maxminddb::Reader::open_readfile(mmdb_path)
-            .map_err(|e| anyhow!("failed to read mmdb file err={}", e))?;
-        Ok(Self { reader })
+    /// takes a City database, a Country database also works with city fields left empty
+    pub fn new(mmdb_path: String) -> Result<Self, GeoError> {
+        Ok(Self {
+            city: MmdbFile::open(mmdb_path, &["-City", "-Country"])?,
+            asn: None,
+            isp: None,
+            connection_type: None,
+            anonymous_ip: None,
+        })
+    }
+
+    /// adds a GeoLite2-ASN or GeoIP2-ASN database
+    pub fn with_asn(mut self, mmdb_path: String) -> Result<Self, GeoError> {
+        self.asn = Some(MmdbFile::open(mmdb_path, &["-ASN"])?);
+        Ok(self)
+    }
+
+    /// adds a GeoIP2-ISP database, it is preferred over the asn database
+    pub fn with_isp(mut self, mmdb_path: String) -> Result<Self, GeoError> {
+        self.isp = Some(MmdbFile::open(mmdb_path, &["-ISP"])?);
+        Ok(self)
+    }
+
+    /// adds a GeoIP2-Connection-Type database
+    pub fn with_connection_type(mut self, mmdb_path: String) -> Result<Self, GeoError> {
+        self.connection_type = Some(MmdbFile::open(mmdb_path, &["-Connection-Type"])?);
+        Ok(self)
+    }
+
+    /// adds a GeoIP2-Anonymous-IP database, see `PeerAdmission`
+    pub fn with_anonymous_ip(mut self, mmdb_path: String) -> Result<Self, GeoError> {
+        self.anonymous_ip = Some(MmdbFile::open(mmdb_path, &["-Anonymous-IP"])?);
+        Ok(self)
+    }
+
+    pub fn has_anonymous_ip(&self) -> bool {
+        self.anonymous_ip.is_some()
+    }
+
+    /// type of the city database currently used for lookups, like `GeoLite2-City`
+    pub fn database_type(&self) -> String {
+        self.city.reader().metadata.database_type.clone()
+    }
+
+    /// build time of the city database currently used for lookups, in unix seconds
+    pub fn build_epoch(&self) -> u64 {
+        self.city.reader().metadata.build_epoch
     }
 
-    pub fn get_geo_from_ip_address(self: Arc<Self>, ip_addr: String) -> Result<Geo> {
+    fn files(&self) -> Vec<&MmdbFile> {
+        let mut files = vec![&self.city];
+        files.extend(
+            [
+                &self.asn,
+                &self.isp,
+                &self.connection_type,
+                &self.anonymous_ip,
+            ]
+            .into_iter()
+            .flatten(),
+        );
+        files
+    }
+
+    /// loads every mmdb file again and swaps in the ones that are a newer build of
+    /// the same database, returns whether any was swapped. a file that fails
+    /// keeps its current database and its error is returned once all were tried
+    pub fn reload(&self) -> Result<bool, GeoError> {
+        let mut swapped = false;
+        let mut failed: Option<GeoError> = None;
+        for file in self.files() {
+            match file.reload() {
+                Ok(s) => swapped |= s,
+                Err(e) => failed = failed.or(Some(e)),
+            }
+        }
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(swapped),
+        }
+    }
+
+    /// checks the mmdb files every `interval` and reloads the modified ones,
+    /// runs until the service is dropped by everyone else
+    pub async fn watch(self: Arc<Self>, interval: Duration) {
+        let this = Arc::downgrade(&self);
+        drop(self);
+        loop {
+            tokio::time::sleep(interval).await;
+            let Some(this) = this.upgrade() else {
+                return;
+            };
+            for file in this.files() {
+                let Some(file_modified) = file.modified_since_load() else {
+                    continue;
+                };
+                // a failed reload leaves the loaded mtime alone so the file is
+                // tried again on the next tick, e.g. once it is fully copied
+                if let Err(e) = file.reload() {
+                    warn!(
+                        "geo database reload failed, keeping the current one modified={:?} err={}",
+                        file_modified, e
+                    );
+                }
+            }
+        }
+    }
+
+    /// asn, owner and connection type of the ip, `None` when no asn, isp or
+    /// connection type database is loaded or none of them knows the ip
+    pub fn get_network_from_ip_address(
+        self: Arc<Self>,
+        ip_addr: String,
+    ) -> Result<Option<Network>, GeoError> {
         let ip_addr: IpAddr = ip_addr
-            .clone()
             .parse()
-            .map_err(|e| anyhow!("parse ip addr failed err={}", e))?;
-
-        let geo = self.reader.lookup::<geoip2::City>(ip_addr).map_err(|e| {
-            anyhow!(
-                "no mmdb record for ip_addr={} err={}",
-                ip_addr.to_string(),
-                e
-            )
-        })?;
-
-        let mut geo_city: Option<City> = None;
-        let mut geo_country: Option<Country> = None;
-        let mut geo_continent: Option<Continent> = None;
-        let mut geo_location: Option<Location> = None;
-
-        if let Some(city) = geo.city {
-            geo_city = Some(City {
-                geoname_id: city.geoname_id,
-                name: city
-                    .names
-                    .unwrap_or_default()
-                    .get("en")
-                    .map(|n| n.to_string()),
-            });
+            .map_err(|source| GeoError::InvalidIp { ip_addr, source })?;
+        self.lookup_network(ip_addr)
+    }
+
+    /// flags of the ip in the anonymous ip database, `None` when the database is not
+    /// loaded. an ip the database does not list has no flag set
+    pub fn get_anonymous_ip_from_ip_address(
+        self: Arc<Self>,
+        ip_addr: String,
+    ) -> Result<Option<AnonymousIp>, GeoError> {
+        let ip_addr: IpAddr = ip_addr
+            .parse()
+            .map_err(|source| GeoError::InvalidIp { ip_addr, source })?;
+        let Some(anonymous_ip) = &self.anonymous_ip else {
+            return Ok(None);
+        };
+
+        let reader = anonymous_ip.reader();
+        let record = lookup::<geoip2::AnonymousIp>(&reader, ip_addr)?;
+        Ok(Some(
+            record
+                .map(|r| AnonymousIp {
+                    is_anonymous: r.is_anonymous.unwrap_or_default(),
+                    is_anonymous_vpn: r.is_anonymous_vpn.unwrap_or_default(),
+                    is_hosting_provider: r.is_hosting_provider.unwrap_or_default(),
+                    is_public_proxy: r.is_public_proxy.unwrap_or_default(),
+                    is_residential_proxy: r.is_residential_proxy.unwrap_or_default(),
+                    is_tor_exit_node: r.is_tor_exit_node.unwrap_or_default(),
+                })
+                .unwrap_or_default(),
+        ))
+    }
+
+    fn lookup_network(&self, ip_addr: IpAddr) -> Result<Option<Network>, GeoError> {
+        let mut network = Network::default();
+        let mut found = false;
+
+        if let Some(isp) = &self.isp {
+            let reader = isp.reader();
+            if let Some(isp) = lookup::<geoip2::Isp>(&reader, ip_addr)? {
+                found = true;
+                network.asn = isp.autonomous_system_number;
+                network.as_organization = isp.autonomous_system_organization.map(|o| o.to_owned());
+                network.isp = isp.isp.map(|i| i.to_owned());
+                network.organization = isp.organization.map(|o| o.to_owned());
+            }
         }
-        if let Some(country) = geo.country {
-            geo_country = Some(Country {
-                geoname_id: country.geoname_id,
-                name: country
-                    .names
-                    .unwrap_or_default()
-                    .get("en")
-                    .map(|n| n.to_string()),
-                is_in_european_union: country.is_in_european_union,
-                iso_code: country.iso_code.map(|i| i.to_string()),
-            });
+        if let (Some(asn), None) = (&self.asn, network.asn) {
+            let reader = asn.reader();
+            if let Some(asn) = lookup::<geoip2::Asn>(&reader, ip_addr)? {
+                found = true;
+                network.asn = asn.autonomous_system_number;
+                network.as_organization = asn.autonomous_system_organization.map(|o| o.to_owned());
+            }
         }
-        if let Some(continent) = geo.continent {
-            geo_continent = Some(Continent {
-                geoname_id: continent.geoname_id,
-                name: continent
-                    .names
-                    .unwrap_or_default()
-                    .get("en")
-                    .map(|n| n.to_string()),
-                code: continent.code.map(|c| c.to_string()),
-            });
+        if let Some(connection_type) = &self.connection_type {
+            let reader = connection_type.reader();
+            if let Some(ct) = lookup::<geoip2::ConnectionType>(&reader, ip_addr)? {
+                found = true;
+                network.connection_type = ct.connection_type.and_then(ConnectionType::from_mmdb);
+            }
         }
-        if let Some(location) = geo.location {
-            geo_location = Some(Location {
-                latitude: location.latitude,
-                longitude: location.longitude,
-                metro_code: location.metro_code,
-                time_zone: location.time_zone.map(|t| t.to_string()),
-            });
+
+        Ok(found.then_some(network))
+    }
+
+    /// geo of the ip with names in english, see `get_geo_from_ip_address_with_locales`.
+    /// an ip the database does not know is `GeoError::NotFound`
+    pub fn get_geo_from_ip_address(self: Arc<Self>, ip_addr: String) -> Result<Geo, GeoError> {
+        self.get_geo_from_ip_address_with_locales(ip_addr, vec![])
+    }
+
+    /// same as `get_geo_from_ip_address` but an ip the database does not know,
+    /// or knows nothing about, is `None`
+    pub fn find_geo_from_ip_address(
+        self: Arc<Self>,
+        ip_addr: String,
+    ) -> Result<Option<Geo>, GeoError> {
+        match self.get_geo_from_ip_address(ip_addr) {
+            Ok(geo) => Ok(Some(geo).filter(|g| g.is_known())),
+            Err(GeoError::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e),
         }
+    }
+
+    /// geo of the ip, the `name` fields are in the first of the locales known for
+    /// the place, falling back to english. every known name is kept in `names`
+    pub fn get_geo_from_ip_address_with_locales(
+        self: Arc<Self>,
+        ip_addr: String,
+        locales: Vec<String>,
+    ) -> Result<Geo, GeoError> {
+        let ip_addr: IpAddr = ip_addr
+            .parse()
+            .map_err(|source| GeoError::InvalidIp { ip_addr, source })?;
+
+        let reader = self.city.reader();
+        let geo = reader
+            .lookup::<geoip2::City>(ip_addr)
+            .map_err(|e| GeoError::lookup(ip_addr.to_string(), e))?;
+
+        let geo_city = geo.city.map(|city| {
+            let names = to_names(city.names);
+            City {
+                geoname_id: city.geoname_id,
+                name: localized_name(&names, &locales),
+                names,
+            }
+        });
+        let geo_continent = geo.continent.map(|continent| {
+            let names = to_names(continent.names);
+            Continent {
+                geoname_id: continent.geoname_id,
+                name: localized_name(&names, &locales),
+                names,
+                code: continent.code.map(|c| c.to_string()),
+            }
+        });
+        let geo_location = geo.location.map(|location| Location {
+            accuracy_radius: location.accuracy_radius,
+            latitude: location.latitude,
+            longitude: location.longitude,
+            metro_code: location.metro_code,
+            time_zone: location.time_zone.map(|t| t.to_string()),
+        });
+        let subdivisions = geo
+            .subdivisions
+            .unwrap_or_default()
+            .into_iter()
+            .map(|subdivision| {
+                let names = to_names(subdivision.names);
+                Subdivision {
+                    geoname_id: subdivision.geoname_id,
+                    iso_code: subdivision.iso_code.map(|i| i.to_string()),
+                    name: localized_name(&names, &locales),
+                    names,
+                }
+            })
+            .collect();
+        let represented_country = geo.represented_country.map(|country| {
+            let names = to_names(country.names);
+            Country {
+                geoname_id: country.geoname_id,
+                name: localized_name(&names, &locales),
+                names,
+                is_in_european_union: country.is_in_european_union,
+                iso_code: country.iso_code.map(|i| i.to_string()),
+            }
+        });
+
+        // a failing enrichment database does not fail the city lookup
+        let network = self.lookup_network(ip_addr).unwrap_or_else(|e| {
+            warn!("geo network lookup failed ip_addr={} err={}", ip_addr, e);
+            None
+        });
 
         Ok(Geo {
             city: geo_city,
             continent: geo_continent,
-            country: geo_country,
+            country: geo.country.map(|c| to_country(c, &locales)),
             location: geo_location,
+            network,
+            subdivisions,
+            postal_code: geo.postal.and_then(|p| p.code).map(|c| c.to_string()),
+            registered_country: geo.registered_country.map(|c| to_country(c, &locales)),
+            represented_country,
+        })
+    }
+}
+
+fn to_names(names: Option<BTreeMap<&str, &str>>) -> BTreeMap<String, String> {
+    names
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(locale, name)| (locale.to_owned(), name.to_owned()))
+        .collect()
+}
+
+fn to_country(country: geoip2::city::Country, locales: &[String]) -> Country {
+    let names = to_names(country.names);
+    Country {
+        geoname_id: country.geoname_id,
+        name: localized_name(&names, locales),
+        names,
+        is_in_european_union: country.is_in_european_union,
+        iso_code: country.iso_code.map(|i| i.to_string()),
+    }
+}
+
+/// one mmdb file and the reader loaded from it
+#[derive(Debug)]
+struct MmdbFile {
+    path: String,
+    reader: ArcSwap<Reader<Vec<u8>>>,
+    /// modification time of the file the current reader was loaded from
+    loaded_modified: Mutex<Option<SystemTime>>,
+}
+
+impl MmdbFile {
+    fn open(path: String, kinds: &'static [&'static str]) -> Result<Self, GeoError> {
+        let loaded_modified = modified(&path);
+        let reader = open(&path)?;
+        if let Err(reason) = check_kind(&reader.metadata.database_type, kinds) {
+            return Err(GeoError::Rejected { reason, path });
+        }
+        Ok(Self {
+            path,
+            reader: ArcSwap::from_pointee(reader),
+            loaded_modified: Mutex::new(loaded_modified),
         })
     }
+
+    fn reader(&self) -> Guard<Arc<Reader<Vec<u8>>>> {
+        self.reader.load()
+    }
+
+    /// the modification time of the file when it changed since it was loaded
+    fn modified_since_load(&self) -> Option<SystemTime> {
+        let file_modified = modified(&self.path)?;
+        match Some(file_modified) == *self.loaded_modified.lock().unwrap() {
+            true => None,
+            false => Some(file_modified),
+        }
+    }
+
+    /// loads the file again and swaps it in when it is a newer build of the same database,
+    /// returns whether it was swapped. on any error the current database is kept
+    fn reload(&self) -> Result<bool, GeoError> {
+        let file_modified = modified(&self.path);
+        let reader = open(&self.path)?;
+        let swapped =
+            check_update(&self.reader().metadata, &reader.metadata).map_err(|reason| {
+                GeoError::Rejected {
+                    path: self.path.clone(),
+                    reason,
+                }
+            })?;
+        if swapped {
+            info!(
+                "geo database reloaded path={} database_type={} build_epoch={}",
+                self.path, reader.metadata.database_type, reader.metadata.build_epoch
+            );
+            self.reader.store(Arc::new(reader));
+        }
+        *self.loaded_modified.lock().unwrap() = file_modified;
+        Ok(swapped)
+    }
+}
+
+/// looks up a record, an ip missing from the database is `None`
+fn lookup<'de, T: serde::Deserialize<'de>>(
+    reader: &'de Reader<Vec<u8>>,
+    ip_addr: IpAddr,
+) -> Result<Option<T>, GeoError> {
+    match reader.lookup::<T>(ip_addr) {
+        Ok(record) => Ok(Some(record)),
+        Err(e) => match GeoError::lookup(ip_addr.to_string(), e) {
+            GeoError::NotFound { .. } => Ok(None),
+            e => Err(e),
+        },
+    }
+}
+
+fn open(mmdb_path: &str) -> Result<Reader<Vec<u8>>, GeoError> {
+    maxminddb::Reader::open_readfile(mmdb_path).map_err(|source| GeoError::Open {
+        path: mmdb_path.to_owned(),
+        source,
+    })
+}
+
+fn modified(mmdb_path: &str) -> Option<SystemTime> {
+    std::fs::metadata(mmdb_path).and_then(|m| m.modified()).ok()
+}
+
+/// whether a database type like `GeoLite2-City` ends with one of `kinds`
+fn check_kind(database_type: &str, kinds: &[&str]) -> Result<(), String> {
+    match kinds.iter().any(|kind| database_type.ends_with(kind)) {
+        true => Ok(()),
+        false => Err(format!(
+            "database type {} is not a {} database",
+            database_type,
+            kinds
+                .iter()
+                .map(|kind| kind.trim_start_matches('-'))
+                .collect::<Vec<&str>>()
+                .join(" or ")
+        )),
+    }
+}
+
+/// whether a database with the `new` metadata should replace the `current` one,
+/// a different kind of database or an older build is rejected
+fn check_update(current: &Metadata, new: &Metadata) -> Result<bool, String> {
+    if new.database_type != current.database_type {
+        return Err(format!(
+            "database type changed from {} to {}",
+            current.database_type, new.database_type
+        ));
+    }
+    if new.ip_version != current.ip_version {
+        return Err(format!(
+            "ip version changed from {} to {}",
+            current.ip_version, new.ip_version
+        ));
+    }
+    if new.build_epoch < current.build_epoch {
+        return Err(format!(
+            "build epoch {} is older than the current {}",
+            new.build_epoch, current.build_epoch
+        ));
+    }
+    Ok(new.build_epoch > current.build_epoch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(database_type: &str, build_epoch: u64) -> Metadata {
+        Metadata {
+            binary_format_major_version: 2,
+            binary_format_minor_version: 0,
+            build_epoch,
+            database_type: database_type.to_owned(),
+            description: Default::default(),
+            ip_version: 6,
+            languages: vec!["en".to_owned()],
+            node_count: 0,
+            record_size: 28,
+        }
+    }
+
+    #[test]
+    fn test_check_update() {
+        let current = metadata("GeoLite2-City", 100);
+        assert_eq!(
+            check_update(&current, &metadata("GeoLite2-City", 200)),
+            Ok(true)
+        );
+        // same build, nothing to swap
+        assert_eq!(
+            check_update(&current, &metadata("GeoLite2-City", 100)),
+            Ok(false)
+        );
+        assert!(check_update(&current, &metadata("GeoLite2-City", 50)).is_err());
+        assert!(check_update(&current, &metadata("GeoLite2-ASN", 200)).is_err());
+    }
+
+    #[test]
+    fn test_check_kind() {
+        let city: &[&str] = &["-City", "-Country"];
+        assert!(check_kind("GeoLite2-City", city).is_ok());
+        assert!(check_kind("GeoIP2-Country", city).is_ok());
+        assert!(check_kind("GeoLite2-ASN", city).is_err());
+        assert!(check_kind("GeoIP2-Anonymous-IP", &["-Anonymous-IP"]).is_ok());
+    }
 }
@@ -0,0 +1,199 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use redis::AsyncCommands as _;
+
+use crate::types::geo::Geo;
+
+use super::{
+    geo::GeoService,
+    redis::{DPNRedisKey, RedisService},
+    types::PeerChangedInfo,
+};
+
+/// geo lookups cached in redis so services without the mmdb file can resolve peers
+///
+/// entries are kept per ip address and per peer session, both expire after `ttl`.
+/// a miss falls back to `GeoService` when one is given, otherwise it stays a miss
+#[derive(Debug)]
+pub struct GeoCache {
+    redis_service: Arc<RedisService>,
+    geo_service: Option<Arc<GeoService>>,
+    ttl: Duration,
+}
+
+impl GeoCache {
+    pub fn new(
+        redis_service: Arc<RedisService>,
+        geo_service: Option<Arc<GeoService>>,
+        ttl: Duration,
+    ) -> Self {
+        Self {
+            redis_service,
+            geo_service,
+            ttl,
+        }
+    }
+
+    /// returns the geo of an ip address, looking it up and caching it on a miss
+    pub async fn get_by_ip(self: Arc<Self>, ip_addr: String) -> Result<Option<Geo>> {
+        let k = DPNRedisKey::get_geo_ip_k(ip_addr.clone());
+        if let Some(geo) = self.clone().get_cached(k.clone()).await? {
+            return Ok(Some(geo));
+        }
+        let geo = match self.clone().lookup(ip_addr)? {
+            Some(geo) => geo,
+            None => return Ok(None),
+        };
+        self.clone().set_cached(k, geo.clone()).await?;
+        Ok(Some(geo))
+    }
+
+    /// returns the geo of a peer session, falling back to its ip address on a miss
+    pub async fn get_by_peer(
+        self: Arc<Self>,
+        masternode_id: String,
+        info: PeerChangedInfo,
+    ) -> Result<Option<Geo>> {
+        let k = DPNRedisKey::get_geo_k(masternode_id, info.login_session_id.clone());
+        if let Some(geo) = self.clone().get_cached(k.clone()).await? {
+            return Ok(Some(geo));
+        }
+        let geo = match self.clone().get_by_ip(info.ip.to_string()).await? {
+            Some(geo) => geo,
+            None => return Ok(None),
+        };
+        self.clone().set_cached(k, geo.clone()).await?;
+        Ok(Some(geo))
+    }
+
+    /// returns every peer of the masternode with its geo, if known
+    pub async fn get_peers_geo(
+        self: Arc<Self>,
+        masternode_id: String,
+    ) -> Result<Vec<(PeerChangedInfo, Option<Geo>)>> {
+        let peers = self
+            .redis_service
+            .clone()
+            .get_peers(masternode_id.clone())
+            .await?;
+        let mut rs: Vec<(PeerChangedInfo, Option<Geo>)> = vec![];
+        for info in peers {
+            let geo = self
+                .clone()
+                .get_by_peer(masternode_id.clone(), info.clone())
+                .await?;
+            rs.push((info, geo));
+        }
+        Ok(rs)
+    }
+
+    /// looks up every peer of the masternode and caches its geo in one round trip,
+    /// returns the number of peers cached. needs a `GeoService`
+    pub async fn warm_up(self: Arc<Self>, masternode_id: String) -> Result<usize> {
+        if self.geo_service.is_none() {
+            return Err(anyhow!("geo cache warm up needs a geo service"));
+        }
+        let peers = self
+            .redis_service
+            .clone()
+            .get_peers(masternode_id.clone())
+            .await?;
+
+        let mut pipe = redis::pipe();
+        let mut count = 0;
+        for info in peers {
+            let ip_addr = info.ip.to_string();
+            let geo = match self.clone().lookup(ip_addr.clone()) {
+                Ok(Some(geo)) => geo,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!(
+                        "geo cache warm up skipped peer ip_addr={} err={}",
+                        ip_addr, e
+                    );
+                    continue;
+                }
+            };
+            let geo_str = serde_json::to_string(&geo).unwrap();
+            for k in [
+                DPNRedisKey::get_geo_k(masternode_id.clone(), info.login_session_id),
+                DPNRedisKey::get_geo_ip_k(ip_addr),
+            ] {
+                pipe.cmd("SET")
+                    .arg(self.redis_service.namespaced(k))
+                    .arg(geo_str.clone())
+                    .arg("PX")
+                    .arg(self.ttl.as_millis() as u64)
+                    .ignore();
+            }
+            count += 1;
+        }
+        if count == 0 {
+            return Ok(0);
+        }
+
+        let mut conn = self.redis_service.clone().get_async_conn();
+        pipe.query_async::<_, ()>(&mut conn).await.map_err(|e| {
+            anyhow!(
+                "redis geo cache warm up failed masternode_id={} err={}",
+                masternode_id,
+                e
+            )
+        })?;
+        Ok(count)
+    }
+
+    fn lookup(self: Arc<Self>, ip_addr: String) -> Result<Option<Geo>> {
+        match self.geo_service.clone() {
+            Some(geo_service) => Ok(geo_service.find_geo_from_ip_address(ip_addr)?),
+            None => Ok(None),
+        }
+    }
+
+    async fn get_cached(self: Arc<Self>, k: String) -> Result<Option<Geo>> {
+        let k = self.redis_service.namespaced(k);
+        let mut conn = self.redis_service.clone().get_async_conn();
+        let cached: Option<String> = conn
+            .get(k.clone())
+            .await
+            .map_err(|e| anyhow!("redis cannot get key={} err={}", k, e))?;
+        match cached {
+            Some(obj_str) => match serde_json::from_str::<Geo>(&obj_str) {
+                Ok(geo) => Ok(Some(geo)),
+                Err(e) => {
+                    warn!("geo cache dropped undecodable entry key={} err={}", k, e);
+                    Ok(None)
+                }
+            },
+            None => Ok(None),
+        }
+    }
+
+    async fn set_cached(self: Arc<Self>, k: String, geo: Geo) -> Result<()> {
+        let k = self.redis_service.namespaced(k);
+        let mut conn = self.redis_service.clone().get_async_conn();
+        redis::cmd("SET")
+            .arg(k.clone())
+            .arg(serde_json::to_string(&geo).unwrap())
+            .arg("PX")
+            .arg(self.ttl.as_millis() as u64)
+            .query_async::<_, ()>(&mut conn)
+            .await
+            .map_err(|e| anyhow!("redis cannot set key={} err={}", k, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_geo_k() {
+        // one key per session so each entry expires on its own
+        let k = DPNRedisKey::get_geo_k("ms1".to_owned(), "s1".to_owned());
+        assert_eq!(k, "peer_geo#ms1_s1");
+        assert_ne!(k, DPNRedisKey::get_geo_k("ms1".to_owned(), "s2".to_owned()));
+    }
+}
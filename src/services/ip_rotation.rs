@@ -0,0 +1,360 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::types::{
+    bandwidth::{EphemeralSession, SessionTerminationReason},
+    connection::{PeerIp, PeernodeInfo, ProxyAccData, DEFAULT_IP_ROTATION_PERIOD},
+    msg_queue::{DPNEvent, SessionTerminatedExtra},
+};
+
+use super::{
+    error::{IpRotationError, PeerMatchError, RedisServiceError},
+    peer_matcher::match_peers,
+    redis::{DPNRedisKey, RedisService},
+};
+
+/// field of the rotation hash used when no sticky session key is given
+const DEFAULT_STICKY_KEY: &str = "_";
+/// rotation state of an account not used for this long is dropped
+const STATE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// peer currently handed out to a proxy account or to one of its sticky sessions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RotationState {
+    pub peer: PeernodeInfo,
+    /// session open on `peer`, terminated with `RotatedIP` when the peer is rotated
+    pub session: Option<EphemeralSession>,
+    /// unix seconds
+    pub assigned_at: i64,
+    /// ip the account was rotated away from, not handed out again on the next rotation
+    pub previous_ip: Option<String>,
+    /// bumped on every write so concurrent masternodes don't overwrite each other
+    pub version: u64,
+}
+
+/// what the caller has to do to route the account
+#[derive(Debug, Clone)]
+pub struct Assignment {
+    pub peer: PeernodeInfo,
+    /// whether `peer` differs from the peer handed out before
+    pub changed: bool,
+    /// `SessionTerminated` of the session on the previous peer, publish it
+    /// and close the session when set
+    pub terminated: Option<DPNEvent>,
+}
+
+/// rotates the peer of proxy accounts every `ip_rotation_period` seconds
+///
+/// the current peer of an account, or of each sticky session key of it, is kept
+/// in redis so every masternode hands out the same peer until the rotation is due.
+/// a rotation moves the account to the best other peer given by `match_peers`,
+/// the account stays on its peer when no other one matches
+#[derive(Debug)]
+pub struct IpRotationScheduler {
+    redis_service: Arc<RedisService>,
+    masternode_id: String,
+}
+
+impl IpRotationScheduler {
+    pub fn new(redis_service: Arc<RedisService>, masternode_id: String) -> Self {
+        Self {
+            redis_service,
+            masternode_id,
+        }
+    }
+
+    /// returns the peer to route the account through from the peers available now
+    pub async fn assign(
+        self: Arc<Self>,
+        acc: &ProxyAccData,
+        sticky_key: Option<String>,
+        peers: Vec<PeernodeInfo>,
+    ) -> Result<Assignment, IpRotationError> {
+        let (k, f) = DPNRedisKey::get_ip_rotation_kf(
+            acc.id.clone(),
+            sticky_key.unwrap_or(DEFAULT_STICKY_KEY.to_owned()),
+        );
+        let current = self.clone().get_state(k.clone(), f.clone()).await?;
+        let now = Utc::now().timestamp();
+        let (state, terminated) = match next_state(acc, current.as_ref(), peers, now)? {
+            Some(next) => next,
+            None => {
+                let current = current.unwrap();
+                return Ok(Assignment {
+                    peer: current.peer,
+                    changed: false,
+                    terminated: None,
+                });
+            }
+        };
+        let expected_version = current.as_ref().map(|s| s.version).unwrap_or(0);
+        let (state, terminated) = match self
+            .clone()
+            .compare_and_set(k.clone(), f.clone(), expected_version, &state)
+            .await?
+        {
+            true => (state, terminated),
+            // another masternode assigned a peer first, follow it
+            false => {
+                let state = self.clone().get_state(k.clone(), f.clone()).await?.ok_or(
+                    IpRotationError::NotAssigned {
+                        proxy_acc_id: acc.id.clone(),
+                        sticky_key: f.clone(),
+                    },
+                )?;
+                (state, None)
+            }
+        };
+        Ok(Assignment {
+            changed: current
+                .map(|c| c.peer.peer_id != state.peer.peer_id)
+                .unwrap_or(true),
+            peer: state.peer,
+            terminated: terminated.map(|session| {
+                DPNEvent::SessionTerminated(SessionTerminatedExtra {
+                    masternode_id: self.masternode_id.clone(),
+                    session,
+                    reason: SessionTerminationReason::RotatedIP,
+                })
+            }),
+        })
+    }
+
+    /// records the session opened on the assigned peer so the next rotation terminates it
+    pub async fn set_session(
+        self: Arc<Self>,
+        proxy_acc_id: String,
+        sticky_key: Option<String>,
+        session: EphemeralSession,
+    ) -> Result<(), IpRotationError> {
+        let (k, f) = DPNRedisKey::get_ip_rotation_kf(
+            proxy_acc_id.clone(),
+            sticky_key.unwrap_or(DEFAULT_STICKY_KEY.to_owned()),
+        );
+        let current = self.clone().get_state(k.clone(), f.clone()).await?.ok_or(
+            IpRotationError::NotAssigned {
+                proxy_acc_id: proxy_acc_id.clone(),
+                sticky_key: f.clone(),
+            },
+        )?;
+        let state = RotationState {
+            session: Some(session),
+            version: current.version + 1,
+            ..current.clone()
+        };
+        match self
+            .compare_and_set(k.clone(), f.clone(), current.version, &state)
+            .await?
+        {
+            true => Ok(()),
+            false => Err(IpRotationError::Rotated {
+                proxy_acc_id,
+                sticky_key: f,
+            }),
+        }
+    }
+
+    async fn get_state(
+        self: Arc<Self>,
+        k: String,
+        f: String,
+    ) -> Result<Option<RotationState>, RedisServiceError> {
+        match self.redis_service.clone().hget::<RotationState>(k, f).await {
+            Ok(state) => Ok(Some(state)),
+            Err(e) if e.is_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn compare_and_set(
+        self: Arc<Self>,
+        k: String,
+        f: String,
+        expected_version: u64,
+        state: &RotationState,
+    ) -> Result<bool, RedisServiceError> {
+        let state_str = serde_json::to_string(state).unwrap();
+        let mut conn = self.redis_service.clone().get_async_conn();
+        let updated: i64 = redis::Script::new(COMPARE_AND_SET_SCRIPT)
+            .key(self.redis_service.namespaced(k.clone()))
+            .arg(f.clone())
+            .arg(expected_version)
+            .arg(state_str)
+            .arg(STATE_TTL.as_millis() as u64)
+            .invoke_async(&mut conn)
+            .await
+            .map_err(|e| RedisServiceError::redis(format!("ip rotation key={}:{}", k, f), e))?;
+        Ok(updated == 1)
+    }
+}
+
+fn rotation_period(acc: &ProxyAccData) -> i64 {
+    match acc.ip_rotation_period > 0 {
+        true => acc.ip_rotation_period,
+        false => DEFAULT_IP_ROTATION_PERIOD,
+    }
+}
+
+/// decides what to write for the account at `now`, nothing when the current peer stays.
+/// a peer that left the available peers is replaced without terminating its session,
+/// that session already ended with the peer
+fn next_state(
+    acc: &ProxyAccData,
+    current: Option<&RotationState>,
+    peers: Vec<PeernodeInfo>,
+    now: i64,
+) -> Result<Option<(RotationState, Option<EphemeralSession>)>, PeerMatchError> {
+    let current_available = current.filter(|c| peers.iter().any(|p| p.peer_id == c.peer.peer_id));
+    let due = current_available
+        .map(|c| now - c.assigned_at >= rotation_period(acc))
+        .unwrap_or(true);
+    if !due {
+        return Ok(None);
+    }
+
+    let matched = match_peers(acc, peers)?;
+    let same_ip = |peer: &PeernodeInfo, ip: Option<&String>| {
+        let ip = ip.and_then(|ip| ip.parse::<PeerIp>().ok());
+        ip.is_some() && peer.ip_addr.parse::<PeerIp>().ok() == ip
+    };
+    let current_ip = current_available.map(|c| &c.peer.ip_addr);
+    let previous_ip = current.and_then(|c| c.previous_ip.as_ref());
+    let next = matched
+        .iter()
+        .find(|m| !same_ip(&m.peer, current_ip) && !same_ip(&m.peer, previous_ip))
+        .or_else(|| matched.iter().find(|m| !same_ip(&m.peer, current_ip)))
+        .or_else(|| matched.first())
+        .ok_or(PeerMatchError::NoPeerAvailable {
+            country_geoname_id: acc.country_geoname_id,
+            city_geoname_id: acc.city_geoname_id,
+        })?;
+
+    let version = current.map(|c| c.version).unwrap_or(0) + 1;
+    Ok(Some(match current_available {
+        // nothing else to rotate to, keep the peer for another period
+        Some(c) if c.peer.peer_id == next.peer.peer_id => (
+            RotationState {
+                assigned_at: now,
+                version,
+                ..c.clone()
+            },
+            None,
+        ),
+        _ => (
+            RotationState {
+                peer: next.peer.clone(),
+                session: None,
+                assigned_at: now,
+                previous_ip: current.map(|c| c.peer.ip_addr.clone()),
+                version,
+            },
+            current_available.and_then(|c| c.session.clone()),
+        ),
+    }))
+}
+
+/// writes the rotation state if nobody wrote it since it was read
+/// returns 1 when written, 0 when the version moved on
+///
+/// KEYS[1] ip rotation hash
+/// ARGV[1] sticky session key
+/// ARGV[2] version the state was read at, 0 when there was none
+/// ARGV[3] new state
+/// ARGV[4] ttl of the hash in ms
+const COMPARE_AND_SET_SCRIPT: &str = r#"
+local current = redis.call('HGET', KEYS[1], ARGV[1])
+local version = 0
+if current then
+    version = cjson.decode(current)['version']
+end
+if version ~= tonumber(ARGV[2]) then
+    return 0
+end
+redis.call('HSET', KEYS[1], ARGV[1], ARGV[3])
+redis.call('PEXPIRE', KEYS[1], ARGV[4])
+return 1
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(id: &str, ip: &str, throughput: f64) -> PeernodeInfo {
+        PeernodeInfo {
+            throughput,
+            ..PeernodeInfo::fixture(id, ip)
+        }
+    }
+
+    fn session(peer_addr: &str) -> EphemeralSession {
+        EphemeralSession::new(
+            "client".to_owned(),
+            "0x0".to_owned(),
+            peer_addr.to_owned(),
+            1,
+            1,
+            "login".to_owned(),
+        )
+    }
+
+    #[test]
+    fn test_next_state() {
+        let peers = vec![
+            peer("a", "10.0.0.1", 3.0),
+            peer("b", "10.0.0.2", 2.0),
+            peer("c", "10.0.0.3", 1.0),
+        ];
+        let acc = ProxyAccData {
+            ip_rotation_period: 60,
+            ..ProxyAccData::fixture("acc")
+        };
+
+        let (first, terminated) = next_state(&acc, None, peers.clone(), 0).unwrap().unwrap();
+        assert_eq!(first.peer.peer_id, "a");
+        assert!(terminated.is_none());
+        let first = RotationState {
+            session: Some(session("a")),
+            ..first
+        };
+
+        // not due yet
+        assert!(next_state(&acc, Some(&first), peers.clone(), 59)
+            .unwrap()
+            .is_none());
+
+        // due, the session on the old peer is terminated
+        let (second, terminated) = next_state(&acc, Some(&first), peers.clone(), 60)
+            .unwrap()
+            .unwrap();
+        assert_eq!(second.peer.peer_id, "b");
+        assert_eq!(second.previous_ip.as_deref(), Some("10.0.0.1"));
+        assert_eq!(terminated.unwrap().peer_addr, "a");
+
+        // the account does not go straight back to the ip it left
+        let (third, _) = next_state(&acc, Some(&second), peers.clone(), 120)
+            .unwrap()
+            .unwrap();
+        assert_eq!(third.peer.peer_id, "c");
+
+        // the peer went away, it is replaced at once without a rotated session
+        let (replaced, terminated) = next_state(&acc, Some(&first), peers[1..].to_vec(), 10)
+            .unwrap()
+            .unwrap();
+        assert_eq!(replaced.peer.peer_id, "b");
+        assert!(terminated.is_none());
+
+        // nothing else to rotate to, the peer is kept
+        let (kept, terminated) = next_state(&acc, Some(&first), peers[..1].to_vec(), 60)
+            .unwrap()
+            .unwrap();
+        assert_eq!((kept.peer.peer_id.as_str(), kept.assigned_at), ("a", 60));
+        assert!(kept.session.is_some() && terminated.is_none());
+
+        assert!(matches!(
+            next_state(&acc, Some(&first), vec![], 60),
+            Err(PeerMatchError::NoPeerAvailable { .. })
+        ));
+    }
+}
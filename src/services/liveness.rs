@@ -0,0 +1,293 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use log::{error, info, warn};
+use redis::{AsyncCommands as _, SetExpiry, SetOptions};
+
+use super::{
+    lock::{DistributedLock, LockGuard, STALE_FENCE_ERROR, WRITE_FENCE_TTL},
+    redis::{DPNRedisKey, RedisService},
+};
+
+/// name of the `DistributedLock` taken by `PeerReaper`
+pub const PEER_REAPER_LOCK: &str = "peer_reaper";
+
+/// heartbeat lease held by a running masternode
+///
+/// the lease key expires on its own if the masternode stops renewing it,
+/// which is how `PeerReaper` tells a crashed masternode from a live one
+#[derive(Debug)]
+pub struct MasternodeLease {
+    redis_service: Arc<RedisService>,
+    masternode_id: String,
+    ttl: Duration,
+}
+
+impl MasternodeLease {
+    pub fn new(redis_service: Arc<RedisService>, masternode_id: String, ttl: Duration) -> Self {
+        Self {
+            redis_service,
+            masternode_id,
+            ttl,
+        }
+    }
+
+    /// sets or renews the lease and registers the masternode for reaping
+    pub async fn heartbeat(self: Arc<Self>) -> Result<()> {
+        let mut conn = self.redis_service.clone().get_async_conn();
+        let err = |e| {
+            anyhow!(
+                "redis masternode heartbeat failed masternode_id={} err={}",
+                self.masternode_id,
+                e
+            )
+        };
+        // the lease and the leases set are in different cluster slots,
+        // which a cluster refuses in one pipeline
+        conn.set_options::<_, _, ()>(
+            self.redis_service
+                .namespaced(DPNRedisKey::get_masternode_lease_k(
+                    self.masternode_id.clone(),
+                )),
+            Utc::now().timestamp(),
+            SetOptions::default().with_expiration(SetExpiry::PX(self.ttl.as_millis() as usize)),
+        )
+        .await
+        .map_err(err)?;
+        conn.sadd::<_, _, ()>(
+            self.redis_service
+                .namespaced(DPNRedisKey::get_masternode_leases_k()),
+            self.masternode_id.clone(),
+        )
+        .await
+        .map_err(err)
+    }
+
+    /// renews the lease every third of its ttl, never returns
+    pub async fn keep_alive(self: Arc<Self>) {
+        loop {
+            if let Err(e) = self.clone().heartbeat().await {
+                error!("{}", e);
+            }
+            tokio::time::sleep(self.ttl / 3).await;
+        }
+    }
+
+    /// drops the lease on clean shutdown, call it after `remove_all_peers`
+    pub async fn release(self: Arc<Self>) -> Result<()> {
+        let mut conn = self.redis_service.clone().get_async_conn();
+        let err = |e| {
+            anyhow!(
+                "redis masternode lease release failed masternode_id={} err={}",
+                self.masternode_id,
+                e
+            )
+        };
+        // one command per key, see `heartbeat`
+        conn.del::<_, ()>(
+            self.redis_service
+                .namespaced(DPNRedisKey::get_masternode_lease_k(
+                    self.masternode_id.clone(),
+                )),
+        )
+        .await
+        .map_err(err)?;
+        conn.srem::<_, _, ()>(
+            self.redis_service
+                .namespaced(DPNRedisKey::get_masternode_leases_k()),
+            self.masternode_id.clone(),
+        )
+        .await
+        .map_err(err)
+    }
+}
+
+/// cleans up after masternodes whose lease expired
+///
+/// any process may run it, a lock makes sure only one reaper acts at a time.
+/// the lock is renewed between masternodes so a long reap never outlives it
+#[derive(Debug)]
+pub struct PeerReaper {
+    redis_service: Arc<RedisService>,
+    lock: Arc<DistributedLock>,
+    lock_ttl: Duration,
+}
+
+impl PeerReaper {
+    pub fn new(redis_service: Arc<RedisService>, lock_ttl: Duration) -> Self {
+        let lock = DistributedLock::new(
+            vec![redis_service.clone()],
+            PEER_REAPER_LOCK.to_owned(),
+            lock_ttl,
+        );
+        Self {
+            redis_service,
+            lock: Arc::new(lock),
+            lock_ttl,
+        }
+    }
+
+    /// publishes `PeerChanged::Disconnected` for every peer of each expired masternode
+    /// and deletes its peers and peer queue, returns the reaped masternodes with
+    /// the number of peers they held. returns nothing if another reaper holds the lock
+    pub async fn reap(self: Arc<Self>) -> Result<Vec<(String, usize)>> {
+        let mut guard = match self.lock.clone().acquire().await? {
+            Some(guard) => guard,
+            None => return Ok(vec![]),
+        };
+        let result = self.clone().reap_locked(&mut guard).await;
+        // a lost lock is left alone, the release only deletes it while still held
+        self.lock.clone().release(guard).await;
+        result
+    }
+
+    /// runs `reap` on every interval, never returns
+    pub async fn run(self: Arc<Self>, interval: Duration) {
+        loop {
+            match self.clone().reap().await {
+                Ok(reaped) => {
+                    for (masternode_id, peers) in reaped {
+                        info!(
+                            "reaped expired masternode masternode_id={} peers={}",
+                            masternode_id, peers
+                        );
+                    }
+                }
+                Err(e) => error!("peer reaper failed err={}", e),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// renews `guard` as it goes and stops early once the lock is lost
+    async fn reap_locked(self: Arc<Self>, guard: &mut LockGuard) -> Result<Vec<(String, usize)>> {
+        let mut conn = self.redis_service.clone().get_async_conn();
+        let masternode_ids: Vec<String> = conn
+            .smembers(
+                self.redis_service
+                    .namespaced(DPNRedisKey::get_masternode_leases_k()),
+            )
+            .await
+            .map_err(|e| anyhow!("redis get masternode leases failed err={}", e))?;
+
+        let mut reaped: Vec<(String, usize)> = vec![];
+        let ns = |key: String| self.redis_service.namespaced(key);
+        for masternode_id in masternode_ids {
+            if needs_renewal(guard, self.lock_ttl, Instant::now()) {
+                match self.lock.clone().renew(guard).await? {
+                    Some(renewed) => *guard = renewed,
+                    None => {
+                        warn!(
+                            "peer reaper lost its lock, stopping fencing_token={}",
+                            guard.fencing_token
+                        );
+                        break;
+                    }
+                }
+            }
+
+            let peers_k = DPNRedisKey::get_peers_k(masternode_id.clone());
+            let result = redis::Script::new(REAP_MASTERNODE_SCRIPT)
+                .key(ns(DPNRedisKey::get_masternode_lease_k(
+                    masternode_id.clone(),
+                )))
+                .key(ns(peers_k.clone()))
+                .key(ns(DPNRedisKey::get_peer_queue_k(masternode_id.clone())))
+                .key(ns(DPNRedisKey::get_write_fence_k(peers_k)))
+                .arg(ns(DPNRedisKey::get_peers_chan(masternode_id.clone())))
+                .arg(guard.fencing_token)
+                .arg(WRITE_FENCE_TTL.as_millis() as u64)
+                .invoke_async::<_, Option<usize>>(&mut conn)
+                .await;
+            let peers = match result {
+                Ok(Some(peers)) => peers,
+                Ok(None) => continue,
+                // the lock expired and a newer reaper already wrote here
+                Err(e) if e.code() == Some(STALE_FENCE_ERROR) => {
+                    warn!(
+                        "peer reaper was fenced off, stopping fencing_token={}",
+                        guard.fencing_token
+                    );
+                    break;
+                }
+                Err(e) => {
+                    return Err(anyhow!(
+                        "redis reap masternode failed masternode_id={} err={}",
+                        masternode_id,
+                        e
+                    ))
+                }
+            };
+            // the leases set lives in another cluster slot than the script keys. a
+            // masternode coming back in between is registered again by its next heartbeat
+            conn.srem::<_, _, ()>(
+                ns(DPNRedisKey::get_masternode_leases_k()),
+                masternode_id.clone(),
+            )
+            .await
+            .map_err(|e| {
+                anyhow!(
+                    "redis unregister masternode failed masternode_id={} err={}",
+                    masternode_id,
+                    e
+                )
+            })?;
+            reaped.push((masternode_id, peers));
+        }
+        Ok(reaped)
+    }
+}
+
+/// whether the guard must be renewed before reaping the next masternode,
+/// a reap step is only started with at least half of the ttl left
+fn needs_renewal(guard: &LockGuard, ttl: Duration, now: Instant) -> bool {
+    guard.valid_until.saturating_duration_since(now) < ttl / 2
+}
+
+/// KEYS[1] masternode lease, KEYS[2] peers hash, KEYS[3] peer queue, KEYS[4] write fence
+/// of the peers hash
+/// ARGV[1] peers channel, ARGV[2] fencing token of the reaper lock, ARGV[3] fence ttl in ms
+/// returns nil if the lease is still alive, otherwise the number of reaped peers.
+/// the lease is checked in the same script so a masternode coming back is never reaped
+const REAP_MASTERNODE_SCRIPT: &str = r#"
+local highest = tonumber(redis.call('GET', KEYS[4]) or '0')
+if tonumber(ARGV[2]) < highest then
+    return redis.error_reply('STALE_FENCE highest=' .. highest)
+end
+redis.call('SET', KEYS[4], ARGV[2], 'PX', ARGV[3])
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    return false
+end
+local peers = redis.call('HVALS', KEYS[2])
+for _, info in ipairs(peers) do
+    redis.call('PUBLISH', ARGV[1], '{"Disconnected":' .. info .. '}')
+end
+redis.call('DEL', KEYS[2], KEYS[3])
+return #peers
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_needs_renewal() {
+        let ttl = Duration::from_secs(30);
+        let now = Instant::now();
+        let guard = |valid_for: Duration| LockGuard {
+            name: PEER_REAPER_LOCK.to_owned(),
+            token: "token".to_owned(),
+            fencing_token: 1,
+            valid_until: now + valid_for,
+        };
+        assert!(!needs_renewal(&guard(ttl), ttl, now));
+        assert!(!needs_renewal(&guard(ttl / 2), ttl, now));
+        assert!(needs_renewal(&guard(ttl / 3), ttl, now));
+        // an expired guard is renewed too, the renewal tells whether it is still held
+        assert!(needs_renewal(&guard(ttl), ttl, now + ttl * 2));
+    }
+}
@@ -0,0 +1,451 @@
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use log::{error, info, warn};
+use tokio::sync::watch;
+
+use super::redis::{DPNRedisKey, RedisService};
+
+/// share of the ttl kept aside for clock drift between the lock servers
+const CLOCK_DRIFT_FACTOR: f64 = 0.01;
+
+/// a guarded key forgets the highest fencing token written to it after this long,
+/// far longer than any lock is held
+pub const WRITE_FENCE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// error code of the scripts refusing a write whose fencing token is stale
+pub const STALE_FENCE_ERROR: &str = "STALE_FENCE";
+
+static TOKEN_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// a held lock, pass `fencing_token` along with every write done under the lock.
+/// guarded writes remember the highest token in `DPNRedisKey::get_write_fence_k` of
+/// the written key and fail with `STALE_FENCE_ERROR` for a lower one, so a holder
+/// whose lock expired cannot overwrite its successor. the peer reaper and
+/// `RedisService::reload_proxy_accs_fenced` write that way
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockGuard {
+    pub name: String,
+    pub token: String,
+    /// strictly increases with every acquisition of the lock
+    pub fencing_token: u64,
+    /// the lock is only safe to rely on until then
+    pub valid_until: Instant,
+}
+
+impl LockGuard {
+    pub fn is_valid(&self) -> bool {
+        Instant::now() < self.valid_until
+    }
+}
+
+/// redlock style lock over one or more independent redis servers
+///
+/// the lock is held once a majority of the servers granted it within its ttl,
+/// a single server gives a plain lock with the same api
+#[derive(Debug)]
+pub struct DistributedLock {
+    redis_services: Vec<Arc<RedisService>>,
+    name: String,
+    ttl: Duration,
+}
+
+impl DistributedLock {
+    pub fn new(redis_services: Vec<Arc<RedisService>>, name: String, ttl: Duration) -> Self {
+        Self {
+            redis_services,
+            name,
+            ttl,
+        }
+    }
+
+    fn quorum(&self) -> usize {
+        self.redis_services.len() / 2 + 1
+    }
+
+    fn validity(&self, started_at: Instant) -> Option<Instant> {
+        let drift = self.ttl.mul_f64(CLOCK_DRIFT_FACTOR) + Duration::from_millis(2);
+        let valid_until = started_at + self.ttl.checked_sub(drift)?;
+        match Instant::now() < valid_until {
+            true => Some(valid_until),
+            false => None,
+        }
+    }
+
+    /// tries once to take the lock, returns nothing if it is held elsewhere
+    pub async fn acquire(self: Arc<Self>) -> Result<Option<LockGuard>> {
+        if self.redis_services.is_empty() {
+            return Err(anyhow!("lock needs at least one redis name={}", self.name));
+        }
+        let token = format!(
+            "{}_{}_{}",
+            std::process::id(),
+            Utc::now().timestamp_nanos_opt().unwrap_or_default(),
+            TOKEN_SEQ.fetch_add(1, Ordering::Relaxed)
+        );
+        let started_at = Instant::now();
+
+        let mut granted: Vec<(Arc<RedisService>, u64)> = vec![];
+        for redis_service in self.redis_services.iter() {
+            match self
+                .clone()
+                .acquire_one(redis_service.clone(), token.clone())
+                .await
+            {
+                Ok(Some(fence)) => granted.push((redis_service.clone(), fence)),
+                Ok(None) => {}
+                Err(e) => warn!("lock acquire failed name={} err={}", self.name, e),
+            }
+        }
+
+        // raise every granted server to the highest fence so the next holder,
+        // which shares at least one server with this one, gets a higher fence
+        let fencing_token = granted.iter().map(|(_, f)| *f).max().unwrap_or_default();
+        let mut bumped = 0;
+        if granted.len() >= self.quorum() {
+            for (redis_service, _) in granted.iter() {
+                match self
+                    .clone()
+                    .bump_fence(redis_service.clone(), token.clone(), fencing_token)
+                    .await
+                {
+                    Ok(true) => bumped += 1,
+                    Ok(false) => {}
+                    Err(e) => warn!("lock fence bump failed name={} err={}", self.name, e),
+                }
+            }
+        }
+
+        match self.validity(started_at) {
+            Some(valid_until) if bumped >= self.quorum() => Ok(Some(LockGuard {
+                name: self.name.clone(),
+                token,
+                fencing_token,
+                valid_until,
+            })),
+            _ => {
+                self.release_all(&token).await;
+                Ok(None)
+            }
+        }
+    }
+
+    /// extends the lock by a full ttl, returns the renewed guard or nothing
+    /// if the lock was lost in the meantime
+    pub async fn renew(self: Arc<Self>, guard: &LockGuard) -> Result<Option<LockGuard>> {
+        let started_at = Instant::now();
+        let mut renewed = 0;
+        for redis_service in self.redis_services.iter() {
+            let mut conn = redis_service.clone().get_async_conn();
+            let result = redis::Script::new(RENEW_SCRIPT)
+                .key(redis_service.namespaced(DPNRedisKey::get_lock_k(self.name.clone())))
+                .arg(guard.token.clone())
+                .arg(self.ttl.as_millis() as u64)
+                .invoke_async::<_, bool>(&mut conn)
+                .await;
+            match result {
+                Ok(true) => renewed += 1,
+                Ok(false) => {}
+                Err(e) => warn!("lock renew failed name={} err={}", self.name, e),
+            }
+        }
+
+        match self.validity(started_at) {
+            Some(valid_until) if renewed >= self.quorum() => Ok(Some(LockGuard {
+                valid_until,
+                ..guard.clone()
+            })),
+            _ => Ok(None),
+        }
+    }
+
+    /// releases the lock if it is still held by the guard
+    pub async fn release(self: Arc<Self>, guard: LockGuard) {
+        self.release_all(&guard.token).await;
+    }
+
+    async fn release_all(&self, token: &str) {
+        for redis_service in self.redis_services.iter() {
+            let mut conn = redis_service.clone().get_async_conn();
+            if let Err(e) = redis::Script::new(RELEASE_SCRIPT)
+                .key(redis_service.namespaced(DPNRedisKey::get_lock_k(self.name.clone())))
+                .arg(token)
+                .invoke_async::<_, ()>(&mut conn)
+                .await
+            {
+                warn!("lock release failed name={} err={}", self.name, e);
+            }
+        }
+    }
+
+    async fn acquire_one(
+        self: Arc<Self>,
+        redis_service: Arc<RedisService>,
+        token: String,
+    ) -> Result<Option<u64>> {
+        let mut conn = redis_service.clone().get_async_conn();
+        redis::Script::new(ACQUIRE_SCRIPT)
+            .key(redis_service.namespaced(DPNRedisKey::get_lock_k(self.name.clone())))
+            .key(redis_service.namespaced(DPNRedisKey::get_lock_fence_k(self.name.clone())))
+            .arg(token)
+            .arg(self.ttl.as_millis() as u64)
+            .invoke_async(&mut conn)
+            .await
+            .map_err(|e| anyhow!("redis lock acquire failed name={} err={}", self.name, e))
+    }
+
+    async fn bump_fence(
+        self: Arc<Self>,
+        redis_service: Arc<RedisService>,
+        token: String,
+        fencing_token: u64,
+    ) -> Result<bool> {
+        let mut conn = redis_service.clone().get_async_conn();
+        redis::Script::new(BUMP_FENCE_SCRIPT)
+            .key(redis_service.namespaced(DPNRedisKey::get_lock_k(self.name.clone())))
+            .key(redis_service.namespaced(DPNRedisKey::get_lock_fence_k(self.name.clone())))
+            .arg(token)
+            .arg(fencing_token)
+            .invoke_async(&mut conn)
+            .await
+            .map_err(|e| anyhow!("redis lock fence failed name={} err={}", self.name, e))
+    }
+}
+
+/// keeps one instance among several as leader of a named role
+///
+/// the leader renews its lock every `renew_interval`, if a renewal fails
+/// leadership is dropped and `on_lost` called with the fencing token of the term.
+/// singleton jobs check `leader` before each run
+pub struct LeaderElection {
+    lock: Arc<DistributedLock>,
+    renew_interval: Duration,
+    on_lost: Box<dyn Fn(u64) + Send + Sync>,
+    term: Mutex<Option<LockGuard>>,
+    leader_tx: watch::Sender<Option<u64>>,
+}
+
+impl Debug for LeaderElection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LeaderElection")
+            .field("lock", &self.lock)
+            .field("renew_interval", &self.renew_interval)
+            .field("term", &self.term)
+            .finish_non_exhaustive()
+    }
+}
+
+impl LeaderElection {
+    /// `renew_interval` must be well below the lock ttl, a third of it is a good start
+    pub fn new(
+        lock: Arc<DistributedLock>,
+        renew_interval: Duration,
+        on_lost: Box<dyn Fn(u64) + Send + Sync>,
+    ) -> Self {
+        Self {
+            lock,
+            renew_interval,
+            on_lost,
+            term: Mutex::new(None),
+            leader_tx: watch::channel(None).0,
+        }
+    }
+
+    /// the fencing token of the current term if this instance is the leader
+    pub fn leader(self: Arc<Self>) -> Option<u64> {
+        self.term
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|guard| guard.is_valid())
+            .map(|guard| guard.fencing_token)
+    }
+
+    /// notified with the fencing token when elected and with nothing when leadership is lost
+    pub fn subscribe(self: Arc<Self>) -> watch::Receiver<Option<u64>> {
+        self.leader_tx.subscribe()
+    }
+
+    /// campaigns for leadership and renews it, never returns
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            if let Err(e) = self.clone().tick().await {
+                error!("leader election failed err={}", e);
+            }
+            tokio::time::sleep(self.renew_interval).await;
+        }
+    }
+
+    /// gives up leadership, call it on clean shutdown
+    pub async fn resign(self: Arc<Self>) {
+        let term = self.term.lock().unwrap().take();
+        if let Some(guard) = term {
+            self.leader_tx.send_replace(None);
+            self.lock.clone().release(guard).await;
+        }
+    }
+
+    async fn tick(self: Arc<Self>) -> Result<()> {
+        let term = self.term.lock().unwrap().clone();
+        match term {
+            Some(guard) => {
+                let renewed = self.lock.clone().renew(&guard).await?;
+                match renewed {
+                    Some(renewed) => *self.term.lock().unwrap() = Some(renewed),
+                    None => self.lose(guard.fencing_token),
+                }
+            }
+            None => {
+                if let Some(guard) = self.lock.clone().acquire().await? {
+                    info!(
+                        "elected leader name={} fencing_token={}",
+                        guard.name, guard.fencing_token
+                    );
+                    self.leader_tx.send_replace(Some(guard.fencing_token));
+                    *self.term.lock().unwrap() = Some(guard);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn lose(self: Arc<Self>, fencing_token: u64) {
+        warn!(
+            "lost leadership name={} fencing_token={}",
+            self.lock.name, fencing_token
+        );
+        *self.term.lock().unwrap() = None;
+        self.leader_tx.send_replace(None);
+        (self.on_lost)(fencing_token);
+    }
+}
+
+/// KEYS[1] lock, KEYS[2] fence counter
+/// ARGV[1] token, ARGV[2] ttl in ms
+/// returns the incremented fence if the lock was free, otherwise nil
+const ACQUIRE_SCRIPT: &str = r#"
+if redis.call('SET', KEYS[1], ARGV[1], 'NX', 'PX', ARGV[2]) then
+    return redis.call('INCR', KEYS[2])
+end
+return false
+"#;
+
+/// KEYS[1] lock, KEYS[2] fence counter
+/// ARGV[1] token, ARGV[2] fencing token of the acquisition
+/// raises the counter to the fencing token while the lock is still held by the token
+const BUMP_FENCE_SCRIPT: &str = r#"
+if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+if tonumber(redis.call('GET', KEYS[2]) or '0') < tonumber(ARGV[2]) then
+    redis.call('SET', KEYS[2], ARGV[2])
+end
+return 1
+"#;
+
+/// KEYS[1] lock
+/// ARGV[1] token, ARGV[2] ttl in ms
+const RENEW_SCRIPT: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end
+return 0
+"#;
+
+/// KEYS[1] lock
+/// ARGV[1] token
+const RELEASE_SCRIPT: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    redis.call('DEL', KEYS[1])
+end
+return 0
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        services::{error::RedisServiceError, types::ProxyAccChanged},
+        types::connection::ProxyAccData,
+    };
+
+    #[test]
+    fn test_validity() {
+        let lock = DistributedLock::new(vec![], "job".to_owned(), Duration::from_secs(10));
+        assert_eq!(lock.quorum(), 1);
+
+        let started_at = Instant::now();
+        let valid_until = lock.validity(started_at).unwrap();
+        // the drift is taken off the ttl
+        assert!(valid_until < started_at + Duration::from_secs(10));
+        assert!(valid_until > started_at + Duration::from_millis(9_800));
+
+        let expired = Instant::now() - Duration::from_secs(10);
+        assert!(lock.validity(expired).is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a disposable redis server in DPN_TEST_REDIS_URI"]
+    async fn test_lock() {
+        let redis_uri = std::env::var("DPN_TEST_REDIS_URI").unwrap();
+        let redis_service = Arc::new(
+            RedisService::new_with_namespace(redis_uri, "dpn_test".to_owned())
+                .await
+                .unwrap(),
+        );
+        let name = format!("job_{}", Utc::now().timestamp_nanos_opt().unwrap());
+        let lock = Arc::new(DistributedLock::new(
+            vec![redis_service.clone()],
+            name,
+            Duration::from_secs(10),
+        ));
+
+        let first = lock.clone().acquire().await.unwrap().unwrap();
+        assert!(first.is_valid());
+        assert!(lock.clone().acquire().await.unwrap().is_none());
+
+        let renewed = lock.clone().renew(&first).await.unwrap().unwrap();
+        assert_eq!(renewed.fencing_token, first.fencing_token);
+        assert!(renewed.valid_until >= first.valid_until);
+
+        // the next holder gets a higher fence, the old guard can neither renew nor release
+        lock.clone().release(renewed).await;
+        let second = lock.clone().acquire().await.unwrap().unwrap();
+        assert!(second.fencing_token > first.fencing_token);
+        assert!(lock.clone().renew(&first).await.unwrap().is_none());
+        lock.clone().release(first.clone()).await;
+        assert!(lock.clone().acquire().await.unwrap().is_none());
+
+        // a write under the old guard is refused once the new one wrote
+        redis_service
+            .clone()
+            .reload_proxy_accs_fenced(second.fencing_token, vec![ProxyAccData::fixture("a")])
+            .await
+            .unwrap();
+        assert!(matches!(
+            redis_service
+                .clone()
+                .publish_proxy_acc_fenced(
+                    ProxyAccChanged::Created(ProxyAccData::fixture("b")),
+                    first.fencing_token
+                )
+                .await,
+            Err(RedisServiceError::StaleFencingToken { .. })
+        ));
+        assert!(matches!(
+            redis_service
+                .clone()
+                .remove_all_proxy_accs_fenced(first.fencing_token)
+                .await,
+            Err(RedisServiceError::StaleFencingToken { .. })
+        ));
+        lock.clone().release(second).await;
+    }
+}
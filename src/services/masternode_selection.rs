@@ -0,0 +1,256 @@
+use std::cmp::Ordering;
+
+use crate::types::{
+    geo::Geo,
+    masternode::{ActivePeersClients, MasternodeInfo},
+};
+
+/// mean earth radius in km
+const EARTH_RADIUS_KM: f64 = 6371.0088;
+
+/// weights of the parts of a masternode score, a part scores between 0 and 1
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectionWeights {
+    pub distance: f64,
+    pub same_country: f64,
+    pub same_continent: f64,
+    pub load: f64,
+}
+
+impl Default for SelectionWeights {
+    fn default() -> Self {
+        Self {
+            distance: 1.0,
+            same_country: 0.5,
+            same_continent: 0.25,
+            load: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectionConfig {
+    pub weights: SelectionWeights,
+    /// masternodes this far away or further get no distance score
+    pub max_distance_km: f64,
+}
+
+impl Default for SelectionConfig {
+    fn default() -> Self {
+        Self {
+            weights: SelectionWeights::default(),
+            max_distance_km: 10_000.0,
+        }
+    }
+}
+
+/// a registered masternode and its current load, `id` breaks ties between equal scores
+#[derive(Debug, Clone)]
+pub struct MasternodeCandidate {
+    pub id: String,
+    pub info: MasternodeInfo,
+    pub load: ActivePeersClients,
+}
+
+#[derive(Debug, Clone)]
+pub struct RankedMasternode {
+    pub candidate: MasternodeCandidate,
+    pub score: f64,
+    /// `None` when the client or the masternode has no known location
+    pub distance_km: Option<f64>,
+}
+
+/// ranks masternodes for a client by distance, region and load
+///
+/// the ranking is deterministic, equal scores are ordered by distance and then by id
+#[derive(Debug, Default)]
+pub struct MasternodeSelector {
+    config: SelectionConfig,
+}
+
+impl MasternodeSelector {
+    pub fn new(config: SelectionConfig) -> Self {
+        Self { config }
+    }
+
+    /// returns the candidates from the best to the worst for a client at `client_geo`
+    pub fn rank(
+        &self,
+        client_geo: &Geo,
+        candidates: Vec<MasternodeCandidate>,
+    ) -> Vec<RankedMasternode> {
+        let mut ranked: Vec<RankedMasternode> = candidates
+            .into_iter()
+            .map(|candidate| {
+                let (score, distance_km) = self.score(client_geo, &candidate);
+                RankedMasternode {
+                    candidate,
+                    score,
+                    distance_km,
+                }
+            })
+            .collect();
+        ranked.sort_by(|a, b| {
+            b.score
+                .total_cmp(&a.score)
+                .then_with(|| match (a.distance_km, b.distance_km) {
+                    (Some(a), Some(b)) => a.total_cmp(&b),
+                    (Some(_), None) => Ordering::Less,
+                    (None, Some(_)) => Ordering::Greater,
+                    (None, None) => Ordering::Equal,
+                })
+                .then_with(|| a.candidate.id.cmp(&b.candidate.id))
+        });
+        ranked
+    }
+
+    /// returns the best candidate, see `rank`
+    pub fn select(
+        &self,
+        client_geo: &Geo,
+        candidates: Vec<MasternodeCandidate>,
+    ) -> Option<RankedMasternode> {
+        self.rank(client_geo, candidates).into_iter().next()
+    }
+
+    fn score(&self, client_geo: &Geo, candidate: &MasternodeCandidate) -> (f64, Option<f64>) {
+        let weights = &self.config.weights;
+        let masternode_geo = &candidate.info.geo;
+
+        let distance_km = coordinates(client_geo)
+            .zip(coordinates(masternode_geo))
+            .map(|(a, b)| great_circle_distance_km(a, b));
+        let distance_score = match distance_km {
+            Some(d) if self.config.max_distance_km > 0.0 => {
+                1.0 - (d / self.config.max_distance_km).min(1.0)
+            }
+            _ => 0.0,
+        };
+
+        let same_country = match (&client_geo.country, &masternode_geo.country) {
+            (Some(a), Some(b)) => {
+                (a.iso_code.is_some() && a.iso_code == b.iso_code)
+                    || (a.geoname_id.is_some() && a.geoname_id == b.geoname_id)
+            }
+            _ => false,
+        };
+        let same_continent = match (&client_geo.continent, &masternode_geo.continent) {
+            (Some(a), Some(b)) => a.code.is_some() && a.code == b.code,
+            _ => false,
+        };
+
+        let score = weights.distance * distance_score
+            + weights.same_country * f64::from(same_country as u8)
+            + weights.same_continent * f64::from(same_continent as u8)
+            + weights.load * headroom(&candidate.load);
+        (score, distance_km)
+    }
+}
+
+/// share of the peers of a masternode not serving a client, a masternode without peers has none
+fn headroom(load: &ActivePeersClients) -> f64 {
+    if load.active_peers == 0 {
+        return 0.0;
+    }
+    let free = load.active_peers.saturating_sub(load.active_clients);
+    f64::from(free) / f64::from(load.active_peers)
+}
+
+fn coordinates(geo: &Geo) -> Option<(f64, f64)> {
+    let location = geo.location.as_ref().filter(|l| l.is_known())?;
+    location.latitude.zip(location.longitude)
+}
+
+/// haversine distance in km between two `(latitude, longitude)` points in degrees
+pub fn great_circle_distance_km(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (lat_a, lat_b) = (a.0.to_radians(), b.0.to_radians());
+    let d_lat = lat_b - lat_a;
+    let d_lon = (b.1 - a.1).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * h.sqrt().min(1.0).asin()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::geo::{Continent, Country, Location};
+
+    use super::*;
+
+    fn geo(iso_code: &str, continent: &str, latitude: f64, longitude: f64) -> Geo {
+        Geo {
+            country: Some(Country {
+                iso_code: Some(iso_code.to_owned()),
+                ..Default::default()
+            }),
+            continent: Some(Continent {
+                code: Some(continent.to_owned()),
+                ..Default::default()
+            }),
+            location: Some(Location {
+                latitude: Some(latitude),
+                longitude: Some(longitude),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn candidate(
+        id: &str,
+        geo: Geo,
+        active_peers: u32,
+        active_clients: u32,
+    ) -> MasternodeCandidate {
+        MasternodeCandidate {
+            id: id.to_owned(),
+            info: MasternodeInfo {
+                peer_bind: "".to_owned(),
+                client_bind: "".to_owned(),
+                control_bind: "".to_owned(),
+                web_bind: "".to_owned(),
+                root_ca: None,
+                geo,
+            },
+            load: ActivePeersClients {
+                active_peers,
+                active_clients,
+            },
+        }
+    }
+
+    #[test]
+    fn test_rank() {
+        // hanoi to ho chi minh city is about 1140 km
+        let d = great_circle_distance_km((21.03, 105.85), (10.82, 106.63));
+        assert!((d - 1140.0).abs() < 20.0);
+
+        let client = geo("VN", "AS", 21.03, 105.85);
+        let saigon = geo("VN", "AS", 10.82, 106.63);
+        let singapore = geo("SG", "AS", 1.35, 103.82);
+        let frankfurt = geo("DE", "EU", 50.11, 8.68);
+        let ranked = MasternodeSelector::default().rank(
+            &client,
+            vec![
+                candidate("fra", frankfurt, 10, 0),
+                candidate("sgn-busy", saigon.clone(), 10, 10),
+                candidate("sin", singapore, 10, 0),
+                candidate("sgn-b", saigon.clone(), 10, 2),
+                candidate("sgn-a", saigon, 10, 2),
+            ],
+        );
+        let ids: Vec<&str> = ranked.iter().map(|r| r.candidate.id.as_str()).collect();
+        // a full masternode next door loses to a free one in the region
+        assert_eq!(ids, vec!["sgn-a", "sgn-b", "sin", "sgn-busy", "fra"]);
+
+        // nothing known about the client, only the load counts
+        let ranked = MasternodeSelector::default().rank(
+            &Geo::default(),
+            vec![
+                candidate("a", Geo::default(), 10, 5),
+                candidate("b", Geo::default(), 10, 1),
+            ],
+        );
+        assert_eq!(ranked[0].candidate.id, "b");
+        assert_eq!(ranked[0].distance_km, None);
+    }
+}
@@ -0,0 +1,487 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::{stream, StreamExt as _};
+use log::warn;
+use redis::{ErrorKind, RedisError};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::types::{
+    accounting::UserBalance,
+    bandwidth::UserBandwidthPrice,
+    connection::{PeerIp, ProxyAccData},
+};
+
+use super::{
+    error::RedisServiceError,
+    redis::DPNRedisKey,
+    storage::StorageService,
+    subscription::{decode_payload, Subscription},
+    types::{
+        DebitOutcome, PeerChanged, PeerChangedInfo, ProxyAccChanged, VersionedProxyAccChanged,
+    },
+};
+
+/// messages a slow subscriber can lag behind before it starts losing them
+pub const CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    hashes: HashMap<String, HashMap<String, String>>,
+    zsets: HashMap<String, HashMap<String, u32>>,
+    counters: HashMap<String, u64>,
+    channels: HashMap<String, broadcast::Sender<String>>,
+}
+
+impl MemoryState {
+    fn hset(&mut self, key: String, field: String, value: String) {
+        self.hashes.entry(key).or_default().insert(field, value);
+    }
+
+    fn hdel(&mut self, key: &str, field: &str) {
+        if let Some(hash) = self.hashes.get_mut(key) {
+            hash.remove(field);
+            if hash.is_empty() {
+                self.hashes.remove(key);
+            }
+        }
+    }
+
+    fn zrem(&mut self, key: &str, value: &str) {
+        if let Some(zset) = self.zsets.get_mut(key) {
+            zset.remove(value);
+            if zset.is_empty() {
+                self.zsets.remove(key);
+            }
+        }
+    }
+
+    fn hvalues<T: DeserializeOwned>(&self, key: &str) -> Result<Vec<T>> {
+        let mut rs: Vec<T> = vec![];
+        for obj_str in self.hashes.get(key).into_iter().flat_map(|h| h.values()) {
+            let t = serde_json::from_str::<T>(obj_str)
+                .map_err(|e| anyhow!("memory failed to decode err={}", e))?;
+            rs.push(t);
+        }
+        Ok(rs)
+    }
+
+    /// balances are kept as integer strings like `HINCRBY` does
+    fn balance(&self, user_addr: &str) -> Result<i64> {
+        let (k, f) = DPNRedisKey::get_balance_kf(user_addr.to_owned());
+        match self.hashes.get(&k).and_then(|h| h.get(&f)) {
+            Some(balance) => balance
+                .parse::<i64>()
+                .map_err(|e| anyhow!("memory balance is not an integer err={}", e)),
+            None => Ok(0),
+        }
+    }
+
+    fn set_balance(&mut self, user_addr: &str, balance: i64) {
+        let (k, f) = DPNRedisKey::get_balance_kf(user_addr.to_owned());
+        self.hset(k, f, balance.to_string());
+    }
+
+    fn del(&mut self, key: &str) {
+        self.hashes.remove(key);
+        self.zsets.remove(key);
+        self.counters.remove(key);
+    }
+
+    fn incr(&mut self, key: String) -> u64 {
+        let counter = self.counters.entry(key).or_default();
+        *counter += 1;
+        *counter
+    }
+
+    /// applies the change and publishes it with a new version, see `RedisService::publish_proxy_acc`
+    fn publish_proxy_acc(&mut self, proxy_acc_changed: ProxyAccChanged) {
+        let version = self.incr(DPNRedisKey::get_proxy_acc_version_k());
+        match proxy_acc_changed.clone() {
+            ProxyAccChanged::Created(pad) | ProxyAccChanged::Updated(pad) => {
+                let (k, f) = DPNRedisKey::get_proxy_acc_kf(pad.id.clone());
+                self.hset(k, f, serde_json::to_string(&pad).unwrap());
+            }
+            ProxyAccChanged::Deleted(id) => {
+                let (k, f) = DPNRedisKey::get_proxy_acc_kf(id);
+                self.hdel(&k, &f);
+            }
+            ProxyAccChanged::RefreshAll() => { /**/ }
+        }
+        self.publish(
+            DPNRedisKey::get_proxy_acc_chan(),
+            serde_json::to_string(&proxy_acc_changed).unwrap(),
+        );
+        let msg = VersionedProxyAccChanged {
+            version,
+            change: proxy_acc_changed,
+        };
+        self.publish(
+            DPNRedisKey::get_proxy_acc_versioned_chan(),
+            serde_json::to_string(&msg).unwrap(),
+        );
+    }
+
+    fn publish(&mut self, chan_name: String, obj_str: String) {
+        // no receiver is not an error, same as publishing to a channel nobody listens to
+        _ = self.sender(chan_name).send(obj_str);
+    }
+
+    fn sender(&mut self, chan_name: String) -> &broadcast::Sender<String> {
+        self.channels
+            .entry(chan_name)
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+    }
+}
+
+/// in-process `StorageService`, pub/sub goes through an in-memory broadcast
+#[derive(Debug, Default)]
+pub struct InMemoryStorageService {
+    state: Mutex<MemoryState>,
+}
+
+impl InMemoryStorageService {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn subscribe_typed<T>(self: Arc<Self>, chan_name: String) -> Subscription<T>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let rx = self
+            .state
+            .lock()
+            .unwrap()
+            .sender(chan_name.clone())
+            .subscribe();
+        stream::unfold((rx, chan_name), |(mut rx, chan_name)| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(payload) => {
+                        let msg = decode_payload::<T>(&chan_name, payload);
+                        return Some((msg, (rx, chan_name)));
+                    }
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("memory subscriber lagged chan={} skipped={}", chan_name, n);
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+#[async_trait]
+impl StorageService for InMemoryStorageService {
+    async fn hset(self: Arc<Self>, key: String, field: String, value: Value) -> Result<()> {
+        self.state
+            .lock()
+            .unwrap()
+            .hset(key, field, value.to_string());
+        Ok(())
+    }
+
+    async fn hget(self: Arc<Self>, key: String, field: String) -> Result<Value> {
+        let state = self.state.lock().unwrap();
+        let obj_str = state
+            .hashes
+            .get(&key)
+            .and_then(|h| h.get(&field))
+            .ok_or(anyhow!("memory cannot get key={}:{}", key, field))?;
+        serde_json::from_str::<Value>(obj_str)
+            .map_err(|e| anyhow!("memory failed to decode err={}", e))
+    }
+
+    async fn hgetall(self: Arc<Self>, key: String) -> Result<Vec<(String, Value)>> {
+        let state = self.state.lock().unwrap();
+        let mut rs: Vec<(String, Value)> = vec![];
+        for (field, obj_str) in state.hashes.get(&key).into_iter().flatten() {
+            let value = serde_json::from_str::<Value>(obj_str)
+                .map_err(|e| anyhow!("memory failed to decode err={}", e))?;
+            rs.push((field.clone(), value));
+        }
+        Ok(rs)
+    }
+
+    async fn hdel(self: Arc<Self>, key: String, field: String) -> Result<()> {
+        self.state.lock().unwrap().hdel(&key, &field);
+        Ok(())
+    }
+
+    async fn zadd(self: Arc<Self>, key: String, score: u32, value: String) -> Result<()> {
+        self.state
+            .lock()
+            .unwrap()
+            .zsets
+            .entry(key)
+            .or_default()
+            .insert(value, score);
+        Ok(())
+    }
+
+    async fn zrem(self: Arc<Self>, key: String, value: String) -> Result<()> {
+        self.state.lock().unwrap().zrem(&key, &value);
+        Ok(())
+    }
+
+    async fn zsetall(self: Arc<Self>, key: String, score: u32) -> Result<()> {
+        if let Some(zset) = self.state.lock().unwrap().zsets.get_mut(&key) {
+            zset.values_mut().for_each(|s| *s = score);
+        }
+        Ok(())
+    }
+
+    async fn zgetall(self: Arc<Self>, key: String) -> Result<Vec<(String, u32)>> {
+        let state = self.state.lock().unwrap();
+        let mut result: Vec<(String, u32)> = state
+            .zsets
+            .get(&key)
+            .map(|zset| zset.iter().map(|(v, s)| (v.clone(), *s)).collect())
+            .unwrap_or_default();
+        // redis orders members with equal scores by their string representation
+        result.sort_by(|(a, a_score), (b, b_score)| (a_score, a).cmp(&(b_score, b)));
+        Ok(result)
+    }
+
+    async fn del(self: Arc<Self>, key: String) -> Result<()> {
+        self.state.lock().unwrap().del(&key);
+        Ok(())
+    }
+
+    async fn publish(self: Arc<Self>, chan_name: String, obj_str: String) -> Result<()> {
+        self.state.lock().unwrap().publish(chan_name, obj_str);
+        Ok(())
+    }
+
+    async fn subscribe(self: Arc<Self>, chan_name: String) -> Result<Subscription<Value>> {
+        Ok(self.subscribe_typed(chan_name))
+    }
+
+    async fn publish_peer(
+        self: Arc<Self>,
+        masternode_id: String,
+        status: PeerChanged,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        match status.clone() {
+            PeerChanged::Connected(info) => {
+                let (k, f) = DPNRedisKey::get_peers_kf(masternode_id.clone(), info.ip);
+                state.hset(k, f, serde_json::to_string(&info).unwrap());
+            }
+            PeerChanged::Disconnected(info) => {
+                let (k, f) = DPNRedisKey::get_peers_kf(masternode_id.clone(), info.ip);
+                state.hdel(&k, &f);
+            }
+        }
+        state.publish(
+            DPNRedisKey::get_peers_chan(masternode_id),
+            serde_json::to_string(&status).unwrap(),
+        );
+        Ok(())
+    }
+
+    async fn get_peers(self: Arc<Self>, masternode_id: String) -> Result<Vec<PeerChangedInfo>> {
+        let k = DPNRedisKey::get_peers_k(masternode_id);
+        self.state.lock().unwrap().hvalues::<PeerChangedInfo>(&k)
+    }
+
+    async fn remove_all_peers(self: Arc<Self>, masternode_id: String) -> Result<()> {
+        let k = DPNRedisKey::get_peers_k(masternode_id.clone());
+        let mut state = self.state.lock().unwrap();
+        for info in state.hvalues::<PeerChangedInfo>(&k)? {
+            state.publish(
+                DPNRedisKey::get_peers_chan(masternode_id.clone()),
+                serde_json::to_string(&PeerChanged::Disconnected(info)).unwrap(),
+            );
+        }
+        state.del(&k);
+        Ok(())
+    }
+
+    async fn subscribe_peers(
+        self: Arc<Self>,
+        masternode_id: String,
+    ) -> Result<Subscription<PeerChanged>> {
+        Ok(self.subscribe_typed(DPNRedisKey::get_peers_chan(masternode_id)))
+    }
+
+    async fn lease_peer(
+        self: Arc<Self>,
+        masternode_id: String,
+    ) -> Result<Option<(PeerIp, PeerChangedInfo)>> {
+        let queue_k = DPNRedisKey::get_peer_queue_k(masternode_id.clone());
+        let peers_k = DPNRedisKey::get_peers_k(masternode_id);
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let head = state.zsets.get(&queue_k).and_then(|zset| {
+                zset.iter()
+                    .min_by(|(a, a_score), (b, b_score)| (a_score, a).cmp(&(b_score, b)))
+                    .map(|(ip, _)| ip.clone())
+            });
+            let Some(head) = head else {
+                return Ok(None);
+            };
+            match state
+                .hashes
+                .get(&peers_k)
+                .and_then(|h| h.get(&head))
+                .cloned()
+            {
+                Some(info_str) => {
+                    let info = serde_json::from_str::<PeerChangedInfo>(&info_str)
+                        .map_err(|e| anyhow!("memory failed to decode err={}", e))?;
+                    *state
+                        .zsets
+                        .get_mut(&queue_k)
+                        .unwrap()
+                        .get_mut(&head)
+                        .unwrap() += 1;
+                    return Ok(Some((info.ip, info)));
+                }
+                None => state.zrem(&queue_k, &head),
+            }
+        }
+    }
+
+    async fn release_peer(self: Arc<Self>, masternode_id: String, ip: PeerIp) -> Result<()> {
+        let queue_k = DPNRedisKey::get_peer_queue_k(masternode_id);
+        let mut state = self.state.lock().unwrap();
+        if let Some(score) = state
+            .zsets
+            .get_mut(&queue_k)
+            .and_then(|zset| zset.get_mut(&ip.to_string()))
+        {
+            *score = score.saturating_sub(1);
+        }
+        Ok(())
+    }
+
+    async fn get_balance(self: Arc<Self>, user_addr: String) -> Result<UserBalance> {
+        let balance = self.state.lock().unwrap().balance(&user_addr)?;
+        Ok(UserBalance { user_addr, balance })
+    }
+
+    async fn credit(self: Arc<Self>, user_addr: String, amount: i64) -> Result<UserBalance> {
+        if amount < 0 {
+            return Err(RedisServiceError::InvalidArgument(format!(
+                "credit amount must not be negative amount={}",
+                amount
+            ))
+            .into());
+        }
+        let mut state = self.state.lock().unwrap();
+        // fails like HINCRBY does in redis
+        let balance = state
+            .balance(&user_addr)?
+            .checked_add(amount)
+            .ok_or_else(|| {
+                RedisServiceError::redis(
+                    format!("credit balance user_addr={}", user_addr),
+                    RedisError::from((
+                        ErrorKind::ResponseError,
+                        "increment or decrement would overflow",
+                    )),
+                )
+            })?;
+        state.set_balance(&user_addr, balance);
+        Ok(UserBalance { user_addr, balance })
+    }
+
+    async fn debit_if_sufficient(
+        self: Arc<Self>,
+        user_addr: String,
+        amount: i64,
+    ) -> Result<DebitOutcome> {
+        if amount < 0 {
+            return Err(RedisServiceError::InvalidArgument(format!(
+                "debit amount must not be negative amount={}",
+                amount
+            ))
+            .into());
+        }
+        let mut state = self.state.lock().unwrap();
+        let balance = state.balance(&user_addr)?;
+        if balance < amount {
+            return Ok(DebitOutcome::Insufficient(UserBalance {
+                user_addr,
+                balance,
+            }));
+        }
+        state.set_balance(&user_addr, balance - amount);
+        Ok(DebitOutcome::Debited(UserBalance {
+            user_addr,
+            balance: balance - amount,
+        }))
+    }
+
+    async fn publish_peer_price(self: Arc<Self>, price: UserBandwidthPrice) -> Result<()> {
+        let (k, f) = DPNRedisKey::get_price_kf(price.user_addr.clone());
+        let mut state = self.state.lock().unwrap();
+        state.hset(k, f, serde_json::to_string(&price).unwrap());
+        state.publish(
+            DPNRedisKey::get_price_chan(),
+            serde_json::to_string(&price).unwrap(),
+        );
+        Ok(())
+    }
+
+    async fn get_peers_price(self: Arc<Self>) -> Result<Vec<UserBandwidthPrice>> {
+        let (k, _) = DPNRedisKey::get_price_kf("".to_string());
+        self.state.lock().unwrap().hvalues::<UserBandwidthPrice>(&k)
+    }
+
+    async fn publish_proxy_acc(self: Arc<Self>, proxy_acc_changed: ProxyAccChanged) -> Result<()> {
+        self.state
+            .lock()
+            .unwrap()
+            .publish_proxy_acc(proxy_acc_changed);
+        Ok(())
+    }
+
+    async fn get_proxy_accs(self: Arc<Self>) -> Result<Vec<ProxyAccData>> {
+        let (k, _) = DPNRedisKey::get_proxy_acc_kf("".to_string());
+        self.state.lock().unwrap().hvalues::<ProxyAccData>(&k)
+    }
+
+    async fn get_proxy_accs_snapshot(self: Arc<Self>) -> Result<(u64, Vec<ProxyAccData>)> {
+        let (k, _) = DPNRedisKey::get_proxy_acc_kf("".to_string());
+        let state = self.state.lock().unwrap();
+        let version = state
+            .counters
+            .get(&DPNRedisKey::get_proxy_acc_version_k())
+            .cloned()
+            .unwrap_or_default();
+        Ok((version, state.hvalues::<ProxyAccData>(&k)?))
+    }
+
+    async fn get_proxy_acc_version(self: Arc<Self>) -> Result<u64> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .counters
+            .get(&DPNRedisKey::get_proxy_acc_version_k())
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn remove_all_proxy_accs(self: Arc<Self>) -> Result<()> {
+        let (k, _) = DPNRedisKey::get_proxy_acc_kf("".to_owned());
+        let mut state = self.state.lock().unwrap();
+        state.del(&k);
+        state.publish_proxy_acc(ProxyAccChanged::RefreshAll());
+        Ok(())
+    }
+
+    async fn subscribe_proxy_accs(
+        self: Arc<Self>,
+    ) -> Result<Subscription<VersionedProxyAccChanged>> {
+        Ok(self.subscribe_typed(DPNRedisKey::get_proxy_acc_versioned_chan()))
+    }
+}
@@ -1,3 +1,20 @@
+pub mod admission;
+pub mod error;
 pub mod geo;
+pub mod geo_cache;
+pub mod ip_rotation;
+pub mod liveness;
+pub mod lock;
+pub mod masternode_selection;
+pub mod memory;
+pub mod peer_matcher;
+pub mod proxy_acc_replica;
+pub mod proxy_auth;
+pub mod rate_limit;
 pub mod redis;
+pub mod redis_batch;
+pub mod redis_conn;
+pub mod storage;
+pub mod subscription;
 pub mod types;
+pub mod uptime_xp;
@@ -0,0 +1,194 @@
+use crate::types::connection::{PeerIp, PeernodeInfo, PrioritizedIPLevel, ProxyAccData};
+
+use super::error::PeerMatchError;
+
+/// why a peer was chosen for a proxy account, ordered from the best match
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MatchTier {
+    /// the peer has the prioritized ip of the account
+    PrioritizedIp,
+    /// the peer is in the city of the account
+    City,
+    /// the peer is in the country of the account, either no city was asked
+    /// or no peer of the city is available
+    Country,
+}
+
+#[derive(Debug, Clone)]
+pub struct MatchedPeer {
+    pub peer: PeernodeInfo,
+    pub tier: MatchTier,
+}
+
+/// returns the peers a proxy account may use from the best to the worst
+///
+/// peers charging more than the account per kb or per second are left out. a peer
+/// with the prioritized ip comes first, with `PrioritizedIPLevel::Strict` it is the
+/// only choice and its absence is an error, otherwise the account falls back to its
+/// city and then to its country. peers of a tier are ordered by throughput and then by id
+pub fn match_peers(
+    acc: &ProxyAccData,
+    peers: Vec<PeernodeInfo>,
+) -> Result<Vec<MatchedPeer>, PeerMatchError> {
+    let prioritized_ip = acc
+        .prioritized_ip
+        .as_ref()
+        .map(|ip| {
+            ip.parse::<PeerIp>()
+                .map_err(|e| PeerMatchError::InvalidPrioritizedIp {
+                    ip: ip.clone(),
+                    source: e,
+                })
+        })
+        .transpose()?;
+    let strict = matches!(acc.prioritized_ip_level, Some(PrioritizedIPLevel::Strict));
+
+    let mut matched: Vec<MatchedPeer> = peers
+        .into_iter()
+        .filter(|peer| within_rates(acc, peer))
+        .filter_map(|peer| {
+            let tier = tier(acc, prioritized_ip, &peer)?;
+            Some(MatchedPeer { peer, tier })
+        })
+        .collect();
+
+    if let (Some(ip), true) = (prioritized_ip, strict) {
+        matched.retain(|m| m.tier == MatchTier::PrioritizedIp);
+        if matched.is_empty() {
+            return Err(PeerMatchError::PrioritizedIpUnavailable { ip: ip.to_string() });
+        }
+    }
+
+    matched.sort_by(|a, b| {
+        a.tier
+            .cmp(&b.tier)
+            .then_with(|| b.peer.throughput.total_cmp(&a.peer.throughput))
+            .then_with(|| a.peer.peer_id.cmp(&b.peer.peer_id))
+    });
+    Ok(matched)
+}
+
+/// returns the best peer for a proxy account, see `match_peers`
+pub fn select_peer(
+    acc: &ProxyAccData,
+    peers: Vec<PeernodeInfo>,
+) -> Result<MatchedPeer, PeerMatchError> {
+    match_peers(acc, peers)?
+        .into_iter()
+        .next()
+        .ok_or(PeerMatchError::NoPeerAvailable {
+            country_geoname_id: acc.country_geoname_id,
+            city_geoname_id: acc.city_geoname_id,
+        })
+}
+
+/// a negative rate of the account only allows free peers
+fn within_rates(acc: &ProxyAccData, peer: &PeernodeInfo) -> bool {
+    let max_per_kb = u64::try_from(acc.rate_per_kb).unwrap_or(0);
+    let max_per_second = u64::try_from(acc.rate_per_second).unwrap_or(0);
+    peer.rate_per_kb <= max_per_kb && peer.rate_per_second <= max_per_second
+}
+
+fn tier(
+    acc: &ProxyAccData,
+    prioritized_ip: Option<PeerIp>,
+    peer: &PeernodeInfo,
+) -> Option<MatchTier> {
+    // ip_addr may be written as ipv4 in any form, compare the parsed ips
+    if prioritized_ip.is_some() && peer.ip_addr.parse::<PeerIp>().ok() == prioritized_ip {
+        return Some(MatchTier::PrioritizedIp);
+    }
+    if i64::from(peer.country_geoname_id) != acc.country_geoname_id {
+        return None;
+    }
+    match acc.city_geoname_id {
+        Some(city) if i64::from(peer.city_geoname_id) == city => Some(MatchTier::City),
+        _ => Some(MatchTier::Country),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VN: u32 = 1562822;
+    const HANOI: u32 = 1581130;
+    const SAIGON: u32 = 1566083;
+    const SG: u32 = 1880251;
+
+    fn acc(city: Option<u32>, prioritized_ip: Option<(&str, PrioritizedIPLevel)>) -> ProxyAccData {
+        ProxyAccData {
+            country_geoname_id: VN.into(),
+            city_geoname_id: city.map(i64::from),
+            rate_per_kb: 10,
+            rate_per_second: 10,
+            prioritized_ip: prioritized_ip.as_ref().map(|(ip, _)| ip.to_string()),
+            prioritized_ip_level: prioritized_ip.map(|(_, level)| level),
+            ..ProxyAccData::fixture("acc")
+        }
+    }
+
+    fn peer(
+        id: &str,
+        ip: &str,
+        country: u32,
+        city: u32,
+        throughput: f64,
+        rate: u64,
+    ) -> PeernodeInfo {
+        PeernodeInfo {
+            throughput,
+            rate_per_kb: rate,
+            rate_per_second: rate,
+            city_geoname_id: city,
+            country_geoname_id: country,
+            ..PeernodeInfo::fixture(id, ip)
+        }
+    }
+
+    fn ids(matched: &[MatchedPeer]) -> Vec<&str> {
+        matched.iter().map(|m| m.peer.peer_id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_match_peers() {
+        let peers = vec![
+            peer("sgn-slow", "10.0.0.1", VN, SAIGON, 1.0, 5),
+            peer("sgn-fast", "10.0.0.2", VN, SAIGON, 9.0, 5),
+            peer("han", "10.0.0.3", VN, HANOI, 5.0, 5),
+            peer("han-pricey", "10.0.0.4", VN, HANOI, 9.0, 50),
+            peer("sin", "2001:db8::1", SG, 0, 9.0, 5),
+        ];
+
+        // city first, then the rest of the country, pricey and foreign peers are out
+        let matched = match_peers(&acc(Some(HANOI), None), peers.clone()).unwrap();
+        assert_eq!(ids(&matched), vec!["han", "sgn-fast", "sgn-slow"]);
+        assert_eq!(matched[0].tier, MatchTier::City);
+
+        // no peer in the city, fall back to the country
+        let matched = match_peers(&acc(Some(1), None), peers.clone()).unwrap();
+        assert_eq!(ids(&matched), vec!["sgn-fast", "han", "sgn-slow"]);
+
+        // the prioritized peer comes first even abroad
+        let normal = acc(None, Some(("2001:db8:0::1", PrioritizedIPLevel::Normal)));
+        let matched = match_peers(&normal, peers.clone()).unwrap();
+        assert_eq!(ids(&matched), vec!["sin", "sgn-fast", "han", "sgn-slow"]);
+        assert_eq!(matched[0].tier, MatchTier::PrioritizedIp);
+
+        let strict = acc(None, Some(("10.0.0.4", PrioritizedIPLevel::Strict)));
+        assert!(matches!(
+            match_peers(&strict, peers.clone()),
+            Err(PeerMatchError::PrioritizedIpUnavailable { .. })
+        ));
+        let normal = acc(None, Some(("10.0.0.4", PrioritizedIPLevel::Normal)));
+        assert_eq!(
+            select_peer(&normal, peers).unwrap().peer.peer_id,
+            "sgn-fast"
+        );
+
+        assert!(matches!(
+            select_peer(&acc(None, None), vec![]),
+            Err(PeerMatchError::NoPeerAvailable { .. })
+        ));
+    }
+}
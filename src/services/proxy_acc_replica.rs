@@ -0,0 +1,253 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use anyhow::{anyhow, Result};
+use futures::StreamExt as _;
+use log::{error, info, warn};
+
+use crate::types::connection::ProxyAccData;
+
+use super::{
+    storage::StorageService,
+    subscription::Subscription,
+    types::{ProxyAccChanged, VersionedProxyAccChanged},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApplyOutcome {
+    /// the change was the next expected version and has been applied
+    Applied,
+    /// the change is not newer than the local state and has been ignored
+    Stale,
+    /// one or more changes were missed, the snapshot must be reloaded
+    Gap,
+}
+
+#[derive(Debug, Default)]
+struct ReplicaState {
+    version: u64,
+    proxy_accs: HashMap<String, ProxyAccData>,
+}
+
+impl ReplicaState {
+    fn load(&mut self, version: u64, proxy_accs: Vec<ProxyAccData>) {
+        self.version = version;
+        self.proxy_accs = proxy_accs
+            .into_iter()
+            .map(|pad| (pad.id.clone(), pad))
+            .collect();
+    }
+
+    fn apply(&mut self, msg: VersionedProxyAccChanged) -> ApplyOutcome {
+        if msg.version <= self.version {
+            return ApplyOutcome::Stale;
+        }
+        if msg.version > self.version + 1 {
+            return ApplyOutcome::Gap;
+        }
+
+        match msg.change {
+            ProxyAccChanged::Created(pad) | ProxyAccChanged::Updated(pad) => {
+                self.proxy_accs.insert(pad.id.clone(), pad);
+            }
+            ProxyAccChanged::Deleted(id) => {
+                self.proxy_accs.remove(&id);
+            }
+            ProxyAccChanged::RefreshAll() => return ApplyOutcome::Gap,
+        }
+        self.version = msg.version;
+        ApplyOutcome::Applied
+    }
+}
+
+/// in-memory copy of the proxy acc hash kept in sync through the versioned proxy acc channel
+///
+/// changes are applied in version order, whenever a change is missed, arrives
+/// out of order or cannot be decoded the whole snapshot is reloaded from storage
+pub struct ProxyAccReplica {
+    storage_service: Arc<dyn StorageService>,
+    state: RwLock<ReplicaState>,
+    subscription: Mutex<Option<Subscription<VersionedProxyAccChanged>>>,
+}
+
+impl Debug for ProxyAccReplica {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProxyAccReplica")
+            .field("storage_service", &self.storage_service)
+            .field("state", &self.state)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ProxyAccReplica {
+    /// subscribes to the versioned proxy acc channel then loads the snapshot,
+    /// call `run` afterwards to keep the replica up to date
+    pub async fn new(storage_service: Arc<dyn StorageService>) -> Result<Arc<Self>> {
+        let subscription = storage_service
+            .clone()
+            .subscribe_proxy_accs()
+            .await
+            .map_err(|e| anyhow!("proxy acc replica subscribe failed err={}", e))?;
+
+        let _self = Arc::new(Self {
+            storage_service,
+            state: RwLock::new(ReplicaState::default()),
+            subscription: Mutex::new(Some(subscription)),
+        });
+        _self.clone().reload().await?;
+        Ok(_self)
+    }
+
+    pub fn get(self: Arc<Self>, id: &str) -> Option<ProxyAccData> {
+        self.state.read().unwrap().proxy_accs.get(id).cloned()
+    }
+
+    pub fn get_all(self: Arc<Self>) -> HashMap<String, ProxyAccData> {
+        self.state.read().unwrap().proxy_accs.clone()
+    }
+
+    pub fn version(self: Arc<Self>) -> u64 {
+        self.state.read().unwrap().version
+    }
+
+    /// replaces the local state with the snapshot held by the storage
+    pub async fn reload(self: Arc<Self>) -> Result<()> {
+        let (version, proxy_accs) = self
+            .storage_service
+            .clone()
+            .get_proxy_accs_snapshot()
+            .await
+            .map_err(|e| anyhow!("proxy acc replica reload failed err={}", e))?;
+        info!(
+            "proxy acc replica reloaded version={} count={}",
+            version,
+            proxy_accs.len()
+        );
+        self.state.write().unwrap().load(version, proxy_accs);
+        Ok(())
+    }
+
+    /// compares the local version with the one held by the storage and reloads if behind,
+    /// useful on an interval to catch changes missed while no new change is published
+    pub async fn sync(self: Arc<Self>) -> Result<()> {
+        let version = self.storage_service.clone().get_proxy_acc_version().await?;
+        if version != self.clone().version() {
+            self.reload().await?;
+        }
+        Ok(())
+    }
+
+    /// applies a single change, reloading the snapshot if a gap is detected
+    pub async fn apply(self: Arc<Self>, msg: VersionedProxyAccChanged) -> Result<ApplyOutcome> {
+        let version = msg.version;
+        let outcome = self.state.write().unwrap().apply(msg);
+        if outcome == ApplyOutcome::Gap {
+            warn!(
+                "proxy acc replica gap detected local={} received={}",
+                self.clone().version(),
+                version
+            );
+            self.reload().await?;
+        }
+        Ok(outcome)
+    }
+
+    /// consumes the versioned proxy acc channel until it ends, can only be called once
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        let mut subscription = self
+            .subscription
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(anyhow!("proxy acc replica is already running"))?;
+
+        while let Some(msg) = subscription.next().await {
+            let result = match msg {
+                Ok(msg) => self.clone().apply(msg).await.map(|_| ()),
+                Err(e) => {
+                    warn!("proxy acc replica received undecodable change err={}", e);
+                    self.clone().reload().await
+                }
+            };
+            if let Err(e) = result {
+                error!("proxy acc replica failed to sync err={}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::memory::InMemoryStorageService;
+
+    fn msg(version: u64, change: ProxyAccChanged) -> VersionedProxyAccChanged {
+        VersionedProxyAccChanged { version, change }
+    }
+
+    #[test]
+    fn test_apply_in_order() {
+        let mut state = ReplicaState::default();
+        state.load(3, vec![ProxyAccData::fixture("a")]);
+
+        let outcome = state.apply(msg(4, ProxyAccChanged::Created(ProxyAccData::fixture("b"))));
+        assert_eq!(outcome, ApplyOutcome::Applied);
+        let outcome = state.apply(msg(5, ProxyAccChanged::Deleted("a".to_owned())));
+        assert_eq!(outcome, ApplyOutcome::Applied);
+
+        assert_eq!(state.version, 5);
+        assert!(state.proxy_accs.contains_key("b"));
+        assert!(!state.proxy_accs.contains_key("a"));
+    }
+
+    #[test]
+    fn test_apply_stale_and_gap() {
+        let mut state = ReplicaState::default();
+        state.load(3, vec![ProxyAccData::fixture("a")]);
+
+        let outcome = state.apply(msg(3, ProxyAccChanged::Deleted("a".to_owned())));
+        assert_eq!(outcome, ApplyOutcome::Stale);
+        let outcome = state.apply(msg(5, ProxyAccChanged::Created(ProxyAccData::fixture("b"))));
+        assert_eq!(outcome, ApplyOutcome::Gap);
+        let outcome = state.apply(msg(4, ProxyAccChanged::RefreshAll()));
+        assert_eq!(outcome, ApplyOutcome::Gap);
+
+        assert_eq!(state.version, 3);
+        assert_eq!(state.proxy_accs.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_replica_follows_storage() {
+        let storage = Arc::new(InMemoryStorageService::new());
+        storage
+            .clone()
+            .publish_proxy_acc(ProxyAccChanged::Created(ProxyAccData::fixture("a")))
+            .await
+            .unwrap();
+
+        let replica = ProxyAccReplica::new(storage.clone()).await.unwrap();
+        assert!(replica.clone().get("a").is_some());
+
+        // a removal is never published, the next change reveals the gap
+        storage.clone().remove_all_proxy_accs().await.unwrap();
+        storage
+            .clone()
+            .publish_proxy_acc(ProxyAccChanged::Created(ProxyAccData::fixture("b")))
+            .await
+            .unwrap();
+
+        let msg = {
+            let mut subscription = replica.subscription.lock().unwrap().take().unwrap();
+            subscription.next().await.unwrap().unwrap()
+        };
+        let outcome = replica.clone().apply(msg).await.unwrap();
+        assert_eq!(outcome, ApplyOutcome::Gap);
+        assert!(replica.clone().get("a").is_none());
+        assert!(replica.clone().get("b").is_some());
+        assert_eq!(replica.version(), 3);
+    }
+}
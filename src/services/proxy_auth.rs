@@ -0,0 +1,535 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    hash::Hash,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use ipnet::IpNet;
+use log::warn;
+use mockall::automock;
+use subtle::ConstantTimeEq;
+
+use crate::{
+    types::connection::{ProxyAccData, VerifyProxyAccData},
+    utils::hash::hash,
+};
+
+use super::{error::ProxyAuthError, proxy_acc_replica::ProxyAccReplica};
+
+/// where the verifier reads proxy accs from
+#[automock]
+pub trait ProxyAccStore: Debug + Send + Sync + 'static {
+    fn get_proxy_acc(self: Arc<Self>, id: String) -> Option<ProxyAccData>;
+    fn get_proxy_accs(self: Arc<Self>) -> Vec<ProxyAccData>;
+    /// moves on whenever a proxy acc changed
+    fn version(self: Arc<Self>) -> u64;
+}
+
+impl ProxyAccStore for ProxyAccReplica {
+    fn get_proxy_acc(self: Arc<Self>, id: String) -> Option<ProxyAccData> {
+        self.get(&id)
+    }
+
+    fn get_proxy_accs(self: Arc<Self>) -> Vec<ProxyAccData> {
+        self.get_all().into_values().collect()
+    }
+
+    fn version(self: Arc<Self>) -> u64 {
+        ProxyAccReplica::version(self)
+    }
+}
+
+/// ips and cidr ranges of `ProxyAccData.whitelisted_ip`, separated by commas or spaces
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpWhitelist {
+    nets: Vec<IpNet>,
+}
+
+impl IpWhitelist {
+    pub fn parse(s: &str) -> Result<Self, ProxyAuthError> {
+        let nets = s
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| match entry.contains('/') {
+                true => entry.parse::<IpNet>().map_err(|e| e.to_string()),
+                false => entry
+                    .parse::<IpAddr>()
+                    .map(IpNet::from)
+                    .map_err(|e| e.to_string()),
+            })
+            .collect::<Result<Vec<IpNet>, String>>()
+            .map_err(|reason| ProxyAuthError::InvalidWhitelist {
+                whitelist: s.to_owned(),
+                reason,
+            })?;
+        Ok(Self { nets })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.nets.iter().any(|net| net.contains(ip))
+    }
+}
+
+/// failures are counted per username and client ip, so a client guessing passwords
+/// cannot lock the user out for everybody else. the flip side is that guesses spread
+/// over many client ips are only slowed down by `max_failures` per ip
+#[derive(Debug, Clone)]
+pub struct ProxyAuthConfig {
+    /// failed attempts of a username from one client ip within `failure_window`
+    /// before that pair is blocked
+    pub max_failures: u32,
+    pub failure_window: Duration,
+    pub block_for: Duration,
+    /// an ip no proxy acc whitelists is rejected from cache this long
+    pub unknown_ip_ttl: Duration,
+    /// most failure and unknown ip entries kept each, the ones expiring first
+    /// make room when full
+    pub max_entries: usize,
+}
+
+impl Default for ProxyAuthConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            failure_window: Duration::from_secs(60),
+            block_for: Duration::from_secs(300),
+            unknown_ip_ttl: Duration::from_secs(10),
+            max_entries: 10_000,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Failures {
+    count: u32,
+    first_at: Option<Instant>,
+    blocked_until: Option<Instant>,
+}
+
+impl Failures {
+    /// when the entry stops mattering, the end of the block or of the window
+    fn expires_at(&self, failure_window: Duration) -> Option<Instant> {
+        self.blocked_until
+            .or(self.first_at.map(|first_at| first_at + failure_window))
+    }
+}
+
+#[derive(Debug, Default)]
+struct NegativeCache {
+    failures: HashMap<(String, IpAddr), Failures>,
+    unknown_ips: HashMap<IpAddr, Instant>,
+}
+
+/// parsed whitelists of the store at `version`
+#[derive(Debug, Default)]
+struct Whitelists {
+    version: Option<u64>,
+    entries: Vec<(ProxyAccData, IpWhitelist)>,
+}
+
+impl Whitelists {
+    /// invalid whitelists are warned about once here and left out
+    fn parse(version: u64, proxy_accs: Vec<ProxyAccData>) -> Self {
+        let entries = proxy_accs
+            .into_iter()
+            .filter_map(|p| {
+                let whitelist = IpWhitelist::parse(p.whitelisted_ip.as_ref()?)
+                    .map_err(|e| warn!("proxy acc skipped id={} err={}", p.id, e))
+                    .ok()?;
+                Some((p, whitelist))
+            })
+            .collect();
+        Self {
+            version: Some(version),
+            entries,
+        }
+    }
+}
+
+/// drops the expired entries of `map` and, if it is still full, the one expiring first
+fn make_room<K, V>(
+    map: &mut HashMap<K, V>,
+    max_entries: usize,
+    now: Instant,
+    expires_at: impl Fn(&V) -> Option<Instant>,
+) where
+    K: Clone + Eq + Hash,
+{
+    map.retain(|_, v| expires_at(v).map(|at| now < at).unwrap_or(false));
+    if map.len() < max_entries {
+        return;
+    }
+    let first = map
+        .iter()
+        .min_by_key(|(_, v)| expires_at(v))
+        .map(|(k, _)| k.clone());
+    if let Some(k) = first {
+        map.remove(&k);
+    }
+}
+
+/// checks `VerifyProxyAccData` against the proxy accs of a store
+///
+/// basic auth compares passwords in constant time, ip auth looks for the one proxy acc
+/// whose whitelist holds the ip. failed usernames and unknown ips are remembered per
+/// masternode so guessing is cut short without reaching the store, see `ProxyAuthConfig`
+#[derive(Debug)]
+pub struct ProxyAuthVerifier {
+    store: Arc<dyn ProxyAccStore>,
+    config: ProxyAuthConfig,
+    negative: Mutex<NegativeCache>,
+    whitelists: Mutex<Whitelists>,
+}
+
+impl ProxyAuthVerifier {
+    pub fn new(store: Arc<dyn ProxyAccStore>, config: ProxyAuthConfig) -> Self {
+        Self {
+            store,
+            config,
+            negative: Mutex::new(NegativeCache::default()),
+            whitelists: Mutex::new(Whitelists::default()),
+        }
+    }
+
+    /// `client_ip` is the address the request came from
+    pub fn verify(
+        self: Arc<Self>,
+        data: &VerifyProxyAccData,
+        client_ip: IpAddr,
+    ) -> Result<ProxyAccData, ProxyAuthError> {
+        self.verify_at(data, client_ip, Instant::now())
+    }
+
+    fn verify_at(
+        self: Arc<Self>,
+        data: &VerifyProxyAccData,
+        client_ip: IpAddr,
+        now: Instant,
+    ) -> Result<ProxyAccData, ProxyAuthError> {
+        match data {
+            VerifyProxyAccData::BasicAuth(username, password) => {
+                let failures_k = (username.clone(), client_ip);
+                self.verify_basic_auth(failures_k, password, now)
+            }
+            VerifyProxyAccData::IP(ip) => self.verify_ip(ip, now),
+        }
+    }
+
+    fn verify_basic_auth(
+        self: Arc<Self>,
+        failures_k: (String, IpAddr),
+        password: &str,
+        now: Instant,
+    ) -> Result<ProxyAccData, ProxyAuthError> {
+        self.check_blocked(&failures_k, now)?;
+        let proxy_acc = self.store.clone().get_proxy_acc(failures_k.0.clone());
+        // an unknown username costs as much as a wrong password
+        let expected = proxy_acc
+            .as_ref()
+            .map(|p| p.password.as_str())
+            .unwrap_or("");
+        let matches = passwords_match(expected, password) && proxy_acc.is_some();
+        match (proxy_acc, matches) {
+            (Some(proxy_acc), true) => {
+                self.negative.lock().unwrap().failures.remove(&failures_k);
+                Ok(proxy_acc)
+            }
+            (None, _) => {
+                self.record_failure(failures_k, now);
+                Err(ProxyAuthError::UnknownAccount)
+            }
+            (Some(_), false) => {
+                self.record_failure(failures_k, now);
+                Err(ProxyAuthError::WrongPassword)
+            }
+        }
+    }
+
+    fn verify_ip(self: Arc<Self>, ip: &str, now: Instant) -> Result<ProxyAccData, ProxyAuthError> {
+        let ip_addr = ip
+            .parse::<IpAddr>()
+            .map_err(|e| ProxyAuthError::InvalidIp {
+                ip: ip.to_owned(),
+                source: e,
+            })?;
+        let mut whitelists = self.whitelists.lock().unwrap();
+        let version = self.store.clone().version();
+        if whitelists.version != Some(version) {
+            // an ip unknown before may be whitelisted now
+            *whitelists = Whitelists::parse(version, self.store.clone().get_proxy_accs());
+            self.negative.lock().unwrap().unknown_ips.clear();
+        }
+        {
+            let mut negative = self.negative.lock().unwrap();
+            match negative.unknown_ips.get(&ip_addr) {
+                Some(until) if now < *until => {
+                    return Err(ProxyAuthError::IpNotWhitelisted { ip: ip.to_owned() })
+                }
+                Some(_) => {
+                    negative.unknown_ips.remove(&ip_addr);
+                }
+                None => {}
+            }
+        }
+
+        let mut matched: Vec<ProxyAccData> = whitelists
+            .entries
+            .iter()
+            .filter(|(_, whitelist)| whitelist.contains(&ip_addr))
+            .map(|(p, _)| p.clone())
+            .collect();
+        drop(whitelists);
+        match matched.len() {
+            0 => {
+                let mut negative = self.negative.lock().unwrap();
+                make_room(
+                    &mut negative.unknown_ips,
+                    self.config.max_entries,
+                    now,
+                    |until| Some(*until),
+                );
+                negative
+                    .unknown_ips
+                    .insert(ip_addr, now + self.config.unknown_ip_ttl);
+                Err(ProxyAuthError::IpNotWhitelisted { ip: ip.to_owned() })
+            }
+            1 => Ok(matched.remove(0)),
+            _ => Err(ProxyAuthError::AmbiguousIp {
+                ip: ip.to_owned(),
+                proxy_acc_ids: matched.into_iter().map(|p| p.id).collect(),
+            }),
+        }
+    }
+
+    fn check_blocked(
+        &self,
+        failures_k: &(String, IpAddr),
+        now: Instant,
+    ) -> Result<(), ProxyAuthError> {
+        let negative = self.negative.lock().unwrap();
+        match negative
+            .failures
+            .get(failures_k)
+            .and_then(|f| f.blocked_until)
+        {
+            Some(until) if now < until => Err(ProxyAuthError::Blocked {
+                retry_after: until - now,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn record_failure(&self, failures_k: (String, IpAddr), now: Instant) {
+        let mut negative = self.negative.lock().unwrap();
+        if !negative.failures.contains_key(&failures_k) {
+            let failure_window = self.config.failure_window;
+            make_room(&mut negative.failures, self.config.max_entries, now, |f| {
+                f.expires_at(failure_window)
+            });
+        }
+        let failures = negative.failures.entry(failures_k).or_default();
+        let window_over = failures
+            .first_at
+            .map(|first_at| now.duration_since(first_at) >= self.config.failure_window)
+            .unwrap_or(true);
+        if window_over || failures.blocked_until.is_some() {
+            *failures = Failures {
+                first_at: Some(now),
+                ..Default::default()
+            };
+        }
+        failures.count += 1;
+        if failures.count >= self.config.max_failures {
+            failures.blocked_until = Some(now + self.config.block_for);
+        }
+    }
+}
+
+/// hashes both sides first so neither the content nor the length leaks through timing
+fn passwords_match(expected: &str, given: &str) -> bool {
+    hash(expected.as_bytes())
+        .as_bytes()
+        .ct_eq(hash(given.as_bytes()).as_bytes())
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+
+    fn proxy_acc(id: &str, whitelisted_ip: Option<&str>) -> ProxyAccData {
+        ProxyAccData {
+            whitelisted_ip: whitelisted_ip.map(str::to_owned),
+            ..ProxyAccData::fixture(id)
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let accs = vec![
+            proxy_acc("a", Some("10.0.0.0/24, 2001:db8::1")),
+            proxy_acc("b", Some("192.168.1.7")),
+            proxy_acc("c", Some("10.0.0.9")),
+            proxy_acc("d", None),
+        ];
+        let mut store = MockProxyAccStore::new();
+        let by_id = accs.clone();
+        store
+            .expect_get_proxy_acc()
+            .returning(move |id| by_id.iter().find(|p| p.id == id).cloned());
+        store
+            .expect_get_proxy_accs()
+            .times(1)
+            .returning(move || accs.clone());
+        store.expect_version().return_const(1u64);
+        let verifier = Arc::new(ProxyAuthVerifier::new(
+            Arc::new(store),
+            ProxyAuthConfig {
+                max_failures: 2,
+                ..Default::default()
+            },
+        ));
+        let now = Instant::now();
+        let client: IpAddr = "203.0.113.1".parse().unwrap();
+        let verify =
+            |data: VerifyProxyAccData, now: Instant| verifier.clone().verify_at(&data, client, now);
+        let basic = |u: &str, p: &str| VerifyProxyAccData::BasicAuth(u.to_owned(), p.to_owned());
+        let ip = |ip: &str| VerifyProxyAccData::IP(ip.to_owned());
+
+        assert_eq!(verify(basic("d", "d_password"), now).unwrap().id, "d");
+        assert!(matches!(
+            verify(basic("d", "d_passwor"), now),
+            Err(ProxyAuthError::WrongPassword)
+        ));
+        assert!(matches!(
+            verify(basic("e", "e_password"), now),
+            Err(ProxyAuthError::UnknownAccount)
+        ));
+
+        // the second failure blocks the username, even the right password
+        assert!(verify(basic("d", "guess"), now).is_err());
+        assert!(matches!(
+            verify(basic("d", "d_password"), now),
+            Err(ProxyAuthError::Blocked { .. })
+        ));
+        // but only for the client that failed
+        let other_client: IpAddr = "203.0.113.2".parse().unwrap();
+        assert_eq!(
+            verifier
+                .clone()
+                .verify_at(&basic("d", "d_password"), other_client, now)
+                .unwrap()
+                .id,
+            "d"
+        );
+        let later = now + Duration::from_secs(301);
+        assert_eq!(verify(basic("d", "d_password"), later).unwrap().id, "d");
+
+        assert_eq!(verify(ip("10.0.0.1"), now).unwrap().id, "a");
+        assert_eq!(verify(ip("2001:db8::1"), now).unwrap().id, "a");
+        assert_eq!(verify(ip("192.168.1.7"), now).unwrap().id, "b");
+        assert!(matches!(
+            verify(ip("10.0.0.9"), now),
+            Err(ProxyAuthError::AmbiguousIp { .. })
+        ));
+        assert!(matches!(
+            verify(ip("172.16.0.1"), now),
+            Err(ProxyAuthError::IpNotWhitelisted { .. })
+        ));
+        assert!(matches!(
+            verify(ip("not an ip"), now),
+            Err(ProxyAuthError::InvalidIp { .. })
+        ));
+    }
+
+    #[test]
+    fn test_whitelists_follow_version() {
+        let accs = Arc::new(Mutex::new(vec![proxy_acc("a", Some("not an ip"))]));
+        let version = Arc::new(AtomicU64::new(1));
+        let mut store = MockProxyAccStore::new();
+        let current = accs.clone();
+        store
+            .expect_get_proxy_accs()
+            .times(2)
+            .returning(move || current.lock().unwrap().clone());
+        let current = version.clone();
+        store
+            .expect_version()
+            .returning(move || current.load(Ordering::SeqCst));
+        let verifier = Arc::new(ProxyAuthVerifier::new(
+            Arc::new(store),
+            ProxyAuthConfig::default(),
+        ));
+        let now = Instant::now();
+        let client: IpAddr = "203.0.113.1".parse().unwrap();
+        let ip = VerifyProxyAccData::IP("10.0.0.1".to_owned());
+
+        // the invalid whitelist is parsed once, the unknown ip is cached
+        for _ in 0..3 {
+            assert!(matches!(
+                verifier.clone().verify_at(&ip, client, now),
+                Err(ProxyAuthError::IpNotWhitelisted { .. })
+            ));
+        }
+
+        // a new version is parsed again and forgets the unknown ip at once
+        *accs.lock().unwrap() = vec![proxy_acc("a", Some("10.0.0.1"))];
+        version.store(2, Ordering::SeqCst);
+        assert_eq!(
+            verifier.clone().verify_at(&ip, client, now).unwrap().id,
+            "a"
+        );
+        assert_eq!(
+            verifier.clone().verify_at(&ip, client, now).unwrap().id,
+            "a"
+        );
+    }
+
+    #[test]
+    fn test_negative_cache_bounded() {
+        let mut store = MockProxyAccStore::new();
+        store.expect_get_proxy_acc().returning(|_| None);
+        store.expect_get_proxy_accs().returning(Vec::new);
+        store.expect_version().return_const(1u64);
+        let verifier = Arc::new(ProxyAuthVerifier::new(
+            Arc::new(store),
+            ProxyAuthConfig {
+                max_entries: 3,
+                ..Default::default()
+            },
+        ));
+        let now = Instant::now();
+        let client: IpAddr = "203.0.113.1".parse().unwrap();
+
+        for i in 0..10 {
+            let at = now + Duration::from_millis(i);
+            let basic = VerifyProxyAccData::BasicAuth(format!("user{}", i), "guess".to_owned());
+            let ip = VerifyProxyAccData::IP(format!("172.16.0.{}", i));
+            assert!(verifier.clone().verify_at(&basic, client, at).is_err());
+            assert!(verifier.clone().verify_at(&ip, client, at).is_err());
+        }
+        {
+            let negative = verifier.negative.lock().unwrap();
+            assert_eq!(negative.failures.len(), 3);
+            assert_eq!(negative.unknown_ips.len(), 3);
+            // the oldest entries made room
+            assert!(negative
+                .failures
+                .contains_key(&("user9".to_owned(), client)));
+            assert!(!negative
+                .failures
+                .contains_key(&("user0".to_owned(), client)));
+        }
+
+        // expired entries are pruned on the next insert
+        let later = now + Duration::from_secs(3600);
+        let basic = VerifyProxyAccData::BasicAuth("user10".to_owned(), "guess".to_owned());
+        assert!(verifier.clone().verify_at(&basic, client, later).is_err());
+        assert_eq!(verifier.negative.lock().unwrap().failures.len(), 1);
+    }
+}
@@ -0,0 +1,419 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use log::warn;
+
+use super::redis::{DPNRedisKey, RedisService};
+
+/// limits of a proxy acc, zero means unlimited
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimits {
+    /// requests per second, also the burst a quiet account may spend at once
+    pub requests_per_second: u32,
+    pub max_concurrent_streams: u32,
+    pub bytes_per_period: u64,
+    pub period: Duration,
+    /// a stream not refreshed within this time no longer counts as open
+    pub stream_ttl: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitReason {
+    Requests,
+    ConcurrentStreams,
+    Bandwidth,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RateLimitDecision {
+    Allowed,
+    /// `retry_after` is a hint the proxy client can be given back
+    Limited {
+        reason: RateLimitReason,
+        retry_after: Duration,
+    },
+}
+
+impl RateLimitDecision {
+    fn from_wait(reason: RateLimitReason, wait_ms: i64) -> Self {
+        match wait_ms <= 0 {
+            true => RateLimitDecision::Allowed,
+            false => RateLimitDecision::Limited {
+                reason,
+                retry_after: Duration::from_millis(wait_ms as u64),
+            },
+        }
+    }
+}
+
+/// limits proxy accs across masternodes through redis
+///
+/// requests go through a token bucket, streams hold a slot refreshed while open
+/// and bytes are counted over a sliding window. when redis cannot be reached the
+/// same limits are enforced per masternode from local state
+#[derive(Debug)]
+pub struct RateLimiter {
+    redis_service: Arc<RedisService>,
+    local: Mutex<LocalLimits>,
+}
+
+impl RateLimiter {
+    pub fn new(redis_service: Arc<RedisService>) -> Self {
+        Self {
+            redis_service,
+            local: Mutex::new(LocalLimits::default()),
+        }
+    }
+
+    /// takes a token for one request of the proxy acc
+    pub async fn check_request(
+        self: Arc<Self>,
+        proxy_acc_id: String,
+        limits: &RateLimits,
+    ) -> RateLimitDecision {
+        if limits.requests_per_second == 0 {
+            return RateLimitDecision::Allowed;
+        }
+        let k = DPNRedisKey::get_rate_limit_requests_k(proxy_acc_id.clone());
+        let result = self
+            .clone()
+            .invoke(
+                TOKEN_BUCKET_SCRIPT,
+                k,
+                vec![limits.requests_per_second.to_string()],
+            )
+            .await;
+        let wait_ms = match result {
+            Ok(wait_ms) => wait_ms,
+            Err(e) => {
+                warn!("rate limiter falls back to local state err={}", e);
+                let now = Utc::now().timestamp_millis();
+                self.local
+                    .lock()
+                    .unwrap()
+                    .buckets
+                    .entry(proxy_acc_id)
+                    .or_default()
+                    .take(limits.requests_per_second, now)
+            }
+        };
+        RateLimitDecision::from_wait(RateLimitReason::Requests, wait_ms)
+    }
+
+    /// takes or refreshes a stream slot, call it again before `stream_ttl` runs out
+    /// for as long as the stream is open
+    pub async fn open_stream(
+        self: Arc<Self>,
+        proxy_acc_id: String,
+        stream_id: String,
+        limits: &RateLimits,
+    ) -> RateLimitDecision {
+        if limits.max_concurrent_streams == 0 {
+            return RateLimitDecision::Allowed;
+        }
+        let k = DPNRedisKey::get_rate_limit_streams_k(proxy_acc_id.clone());
+        let result = self
+            .clone()
+            .invoke(
+                STREAM_SLOTS_SCRIPT,
+                k,
+                vec![
+                    stream_id.clone(),
+                    limits.max_concurrent_streams.to_string(),
+                    limits.stream_ttl.as_millis().to_string(),
+                ],
+            )
+            .await;
+        let wait_ms = match result {
+            Ok(wait_ms) => wait_ms,
+            Err(e) => {
+                warn!("rate limiter falls back to local state err={}", e);
+                let now = Utc::now().timestamp_millis();
+                self.local
+                    .lock()
+                    .unwrap()
+                    .streams
+                    .entry(proxy_acc_id)
+                    .or_default()
+                    .open(
+                        stream_id,
+                        limits.max_concurrent_streams,
+                        limits.stream_ttl.as_millis() as i64,
+                        now,
+                    )
+            }
+        };
+        RateLimitDecision::from_wait(RateLimitReason::ConcurrentStreams, wait_ms)
+    }
+
+    pub async fn close_stream(self: Arc<Self>, proxy_acc_id: String, stream_id: String) {
+        if let Some(slots) = self.local.lock().unwrap().streams.get_mut(&proxy_acc_id) {
+            slots.close(&stream_id);
+        }
+        let k = self
+            .redis_service
+            .namespaced(DPNRedisKey::get_rate_limit_streams_k(proxy_acc_id));
+        let mut conn = self.redis_service.clone().get_async_conn();
+        if let Err(e) = redis::cmd("ZREM")
+            .arg(k)
+            .arg(stream_id)
+            .query_async::<_, ()>(&mut conn)
+            .await
+        {
+            warn!("rate limiter close stream failed err={}", e);
+        }
+    }
+
+    /// counts bytes already transferred by the proxy acc, the bytes are always
+    /// counted and the decision tells whether the quota of the period is used up
+    pub async fn consume_bytes(
+        self: Arc<Self>,
+        proxy_acc_id: String,
+        bytes: u64,
+        limits: &RateLimits,
+    ) -> RateLimitDecision {
+        if limits.bytes_per_period == 0 {
+            return RateLimitDecision::Allowed;
+        }
+        let k = DPNRedisKey::get_rate_limit_bytes_k(proxy_acc_id.clone());
+        let result = self
+            .clone()
+            .invoke(
+                SLIDING_WINDOW_SCRIPT,
+                k,
+                vec![
+                    bytes.to_string(),
+                    limits.bytes_per_period.to_string(),
+                    limits.period.as_millis().to_string(),
+                ],
+            )
+            .await;
+        let wait_ms = match result {
+            Ok(wait_ms) => wait_ms,
+            Err(e) => {
+                warn!("rate limiter falls back to local state err={}", e);
+                let now = Utc::now().timestamp_millis();
+                self.local
+                    .lock()
+                    .unwrap()
+                    .windows
+                    .entry(proxy_acc_id)
+                    .or_default()
+                    .add(
+                        bytes,
+                        limits.bytes_per_period,
+                        limits.period.as_millis() as i64,
+                        now,
+                    )
+            }
+        };
+        RateLimitDecision::from_wait(RateLimitReason::Bandwidth, wait_ms)
+    }
+
+    async fn invoke(self: Arc<Self>, script: &str, key: String, args: Vec<String>) -> Result<i64> {
+        let mut conn = self.redis_service.clone().get_async_conn();
+        let script = redis::Script::new(script);
+        let mut invocation = script.prepare_invoke();
+        invocation.key(self.redis_service.namespaced(key.clone()));
+        for arg in args {
+            invocation.arg(arg);
+        }
+        invocation
+            .invoke_async(&mut conn)
+            .await
+            .map_err(|e| anyhow!("redis rate limit failed key={} err={}", key, e))
+    }
+}
+
+#[derive(Debug, Default)]
+struct LocalLimits {
+    buckets: HashMap<String, TokenBucket>,
+    streams: HashMap<String, StreamSlots>,
+    windows: HashMap<String, SlidingWindow>,
+}
+
+/// same algorithms as the scripts below, times are unix milliseconds
+/// and every fn returns how long to wait, zero or less when allowed
+#[derive(Debug, Default)]
+struct TokenBucket {
+    tokens: f64,
+    updated_at: i64,
+}
+
+impl TokenBucket {
+    fn take(&mut self, rate: u32, now: i64) -> i64 {
+        let rate = rate as f64;
+        if self.updated_at == 0 {
+            self.tokens = rate;
+        } else {
+            let elapsed = (now - self.updated_at).max(0) as f64;
+            self.tokens = (self.tokens + elapsed * rate / 1000.0).min(rate);
+        }
+        self.updated_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return 0;
+        }
+        ((1.0 - self.tokens) * 1000.0 / rate).ceil() as i64
+    }
+}
+
+#[derive(Debug, Default)]
+struct StreamSlots {
+    /// stream id to the time its slot expires
+    expires_at: HashMap<String, i64>,
+}
+
+impl StreamSlots {
+    fn open(&mut self, stream_id: String, max: u32, ttl: i64, now: i64) -> i64 {
+        self.expires_at.retain(|_, expires_at| *expires_at > now);
+        if !self.expires_at.contains_key(&stream_id) && self.expires_at.len() >= max as usize {
+            let earliest = self.expires_at.values().min().cloned().unwrap_or(now);
+            return (earliest - now).max(1);
+        }
+        self.expires_at.insert(stream_id, now + ttl);
+        0
+    }
+
+    fn close(&mut self, stream_id: &str) {
+        self.expires_at.remove(stream_id);
+    }
+}
+
+#[derive(Debug, Default)]
+struct SlidingWindow {
+    window_start: i64,
+    current: u64,
+    previous: u64,
+}
+
+impl SlidingWindow {
+    fn add(&mut self, bytes: u64, limit: u64, period: i64, now: i64) -> i64 {
+        let window_start = now - now % period;
+        if window_start != self.window_start {
+            self.previous = match window_start - self.window_start == period {
+                true => self.current,
+                false => 0,
+            };
+            self.current = 0;
+            self.window_start = window_start;
+        }
+        self.current += bytes;
+
+        let elapsed = now - window_start;
+        let weight = (period - elapsed) as f64 / period as f64;
+        let used = self.previous as f64 * weight + self.current as f64;
+        match used > limit as f64 {
+            true => period - elapsed,
+            false => 0,
+        }
+    }
+}
+
+/// KEYS[1] bucket hash
+/// ARGV[1] requests per second
+/// returns 0 if a token was taken, otherwise the ms until the next token
+const TOKEN_BUCKET_SCRIPT: &str = r#"
+local rate = tonumber(ARGV[1])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'updated_at')
+local tokens = tonumber(state[1])
+local updated_at = tonumber(state[2])
+if tokens == nil then
+    tokens = rate
+else
+    tokens = math.min(rate, tokens + math.max(0, now - updated_at) * rate / 1000)
+end
+local wait = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+else
+    wait = math.ceil((1 - tokens) * 1000 / rate)
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated_at', now)
+-- a bucket left alone for a second is full again
+redis.call('PEXPIRE', KEYS[1], 2000)
+return wait
+"#;
+
+/// KEYS[1] stream slots sorted set, scored by expiry
+/// ARGV[1] stream id, ARGV[2] max streams, ARGV[3] slot ttl in ms
+/// returns 0 if the slot was taken or refreshed, otherwise the ms until a slot expires
+const STREAM_SLOTS_SCRIPT: &str = r#"
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local ttl = tonumber(ARGV[3])
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', now)
+if not redis.call('ZSCORE', KEYS[1], ARGV[1]) and redis.call('ZCARD', KEYS[1]) >= tonumber(ARGV[2]) then
+    local earliest = redis.call('ZRANGE', KEYS[1], 0, 0, 'WITHSCORES')
+    return math.max(1, tonumber(earliest[2]) - now)
+end
+redis.call('ZADD', KEYS[1], now + ttl, ARGV[1])
+redis.call('PEXPIRE', KEYS[1], ttl)
+return 0
+"#;
+
+/// KEYS[1] window hash
+/// ARGV[1] bytes, ARGV[2] bytes per period, ARGV[3] period in ms
+/// returns 0 if the quota is not exceeded, otherwise the ms until the window moves on
+const SLIDING_WINDOW_SCRIPT: &str = r#"
+local period = tonumber(ARGV[3])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local window_start = now - now % period
+local state = redis.call('HMGET', KEYS[1], 'window_start', 'current', 'previous')
+local last_start = tonumber(state[1]) or 0
+local current = tonumber(state[2]) or 0
+local previous = tonumber(state[3]) or 0
+if window_start ~= last_start then
+    if window_start - last_start == period then
+        previous = current
+    else
+        previous = 0
+    end
+    current = 0
+end
+current = current + tonumber(ARGV[1])
+redis.call('HSET', KEYS[1], 'window_start', window_start, 'current', current, 'previous', previous)
+redis.call('PEXPIRE', KEYS[1], period * 2)
+local elapsed = now - window_start
+local used = previous * (period - elapsed) / period + current
+if used > tonumber(ARGV[2]) then
+    return period - elapsed
+end
+return 0
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_limits() {
+        let mut bucket = TokenBucket::default();
+        assert_eq!(bucket.take(2, 1_000), 0);
+        assert_eq!(bucket.take(2, 1_000), 0);
+        assert_eq!(bucket.take(2, 1_000), 500);
+        assert_eq!(bucket.take(2, 1_500), 0);
+
+        let mut slots = StreamSlots::default();
+        assert_eq!(slots.open("a".to_owned(), 1, 100, 1_000), 0);
+        assert_eq!(slots.open("a".to_owned(), 1, 100, 1_050), 0);
+        assert_eq!(slots.open("b".to_owned(), 1, 100, 1_060), 90);
+        slots.close("a");
+        assert_eq!(slots.open("b".to_owned(), 1, 100, 1_060), 0);
+
+        let mut window = SlidingWindow::default();
+        assert_eq!(window.add(60, 100, 1_000, 10_000), 0);
+        assert_eq!(window.add(60, 100, 1_000, 10_200), 800);
+        // 90% of the previous window still counts
+        assert_eq!(window.add(10, 100, 1_000, 11_100), 900);
+        assert_eq!(window.add(10, 100, 1_000, 11_900), 0);
+    }
+}
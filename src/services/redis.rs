@@ -1,227 +1,402 @@
-use anyhow::{anyhow, Error, Result};
-use log::{error, info};
-use redis::{Commands as _, Connection, RedisResult};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use redis::{AsyncCommands as _, Connection, FromRedisValue, RedisResult};
 use redis_async::client::{ConnectionBuilder, PubsubConnection};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use std::{collections::HashMap, fmt::Debug, sync::Arc};
-use url::Url;
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    str::FromStr,
+    sync::Arc,
+};
+
+use crate::types::{
+    accounting::UserBalance,
+    bandwidth::UserBandwidthPrice,
+    connection::{PeerIp, ProxyAccData},
+};
+
+use super::error::RedisServiceError;
+use super::lock::WRITE_FENCE_TTL;
+use super::redis_batch::RedisBatch;
+use super::redis_conn::{RedisConn, RedisConnConfig, RedisMode};
+use super::storage::StorageService;
+use super::subscription::{PubsubHub, Subscription};
+use super::types::{
+    DebitOutcome, PeerChanged, PeerChangedInfo, ProxyAccChanged, VersionedProxyAccChanged,
+};
 
-use crate::types::{bandwidth::UserBandwidthPrice, connection::ProxyAccData};
-
-use super::types::{PeerChanged, PeerChangedInfo, ProxyAccChanged};
-
-struct RedisUri {
-    is_tls: bool,
-    password: Option<String>,
-    host: String,
-    port: u16,
+pub struct RedisService {
+    config: RedisConnConfig,
+    /// shared multiplexed connection, reconnects by itself when the link drops
+    conn: RedisConn,
+    pubsub: Arc<PubsubHub>,
+    /// prefix of every key and channel, lets several environments share one redis
+    namespace: String,
 }
 
-#[derive(Debug)]
-pub struct RedisService {
-    client: redis::Client,
-    pubsub_con: PubsubConnection,
+impl Debug for RedisService {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisService")
+            .field("config", &self.config)
+            .field("namespace", &self.namespace)
+            .field("pubsub", &self.pubsub)
+            .finish_non_exhaustive()
+    }
 }
 
 impl RedisService {
     pub async fn new(redis_uri: String) -> Result<Self> {
-        let client = redis::Client::open(redis_uri.clone())
-            .map_err(|e| anyhow!("redis: cannot open client err={}", e))?;
-        _ = client
-            .get_connection()
-            .map_err(|e| anyhow!("redis: cannot get connection err={}", e))?;
-
-        let conn_builder = Self::get_redis_conn_builder_from_uri(&redis_uri)?;
-        let pubsub_con = conn_builder
-            .pubsub_connect()
-            .await
-            .map_err(|e| anyhow!("create pub sub connection failed err={}", e))?;
+        Self::new_with_namespace(redis_uri, "".to_owned()).await
+    }
+
+    /// same as `new` but every key and channel is prefixed with `{namespace}:`,
+    /// an empty namespace keeps the unprefixed keys
+    pub async fn new_with_namespace(redis_uri: String, namespace: String) -> Result<Self> {
+        // hash tags are reserved for keys sharing a cluster slot, see `DPNRedisKey::in_slot_of`
+        if namespace.contains(['{', '}']) {
+            return Err(anyhow!(
+                "redis: namespace must not hold braces namespace={}",
+                namespace
+            ));
+        }
+        let config = RedisConnConfig::from_uri(&redis_uri)
+            .map_err(|e| anyhow!("redis: invalid uri err={}", e))?;
+        let conn = config.get_async_conn().await?;
+
+        let pubsub = PubsubHub::connect(config.clone()).await?;
 
-        Ok(Self { client, pubsub_con })
+        Ok(Self {
+            config,
+            conn,
+            pubsub: Arc::new(pubsub),
+            namespace,
+        })
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.namespace
     }
 
-    fn parse_redis_uri(redis_uri: &str) -> Result<RedisUri> {
-        let parsed_url = Url::parse(redis_uri)?;
+    /// prefixes a key or channel built by `DPNRedisKey` with the namespace of this service
+    pub fn namespaced(&self, key: String) -> String {
+        DPNRedisKey::with_namespace(&self.namespace, key)
+    }
 
-        let is_tls = match parsed_url.scheme() {
-            "redis" => false,
-            "rediss" => true,
-            unknown => {
-                return Err(anyhow::anyhow!(
-                    "invalid scheme, must be 'redis' or 'rediss' unknown {}",
-                    unknown
-                ))
+    /// lists the keys of every key family that exist under the namespace of this service,
+    /// in cluster mode only the keys of the node serving the scan are listed
+    pub async fn list_keys(
+        self: Arc<Self>,
+    ) -> Result<Vec<(RedisKeyFamily, Vec<String>)>, RedisServiceError> {
+        let mut conn = self.conn.clone();
+        let mut rs: Vec<(RedisKeyFamily, Vec<String>)> = vec![];
+        for family in DPNRedisKey::families() {
+            if family.kind == RedisKeyKind::Channel {
+                continue;
             }
-        };
-        let password = parsed_url.password().map(|p| p.to_string());
+            let pattern = self.namespaced(family.pattern.clone());
+            let mut keys: Vec<String> = vec![];
+            {
+                let mut iter = conn
+                    .scan_match::<_, String>(pattern.clone())
+                    .await
+                    .map_err(|e| {
+                        RedisServiceError::redis(format!("scan pattern={}", pattern), e)
+                    })?;
+                while let Some(key) = iter.next_item().await {
+                    keys.push(key);
+                }
+            }
+            keys.sort();
+            keys.dedup();
+            rs.push((family, keys));
+        }
+        Ok(rs)
+    }
 
-        let host = match parsed_url.host_str() {
-            Some(host) => host.to_string(),
-            None => return Err(anyhow::anyhow!("parse host failed")),
-        };
+    /// pubsub connection builder for a standalone uri. the node to use is only known
+    /// once connected for sentinel and cluster uris, use `RedisConnConfig::pubsub_connect`
+    pub fn get_redis_conn_builder_from_uri(redis_uri: &str) -> Result<ConnectionBuilder> {
+        let config =
+            RedisConnConfig::from_uri(redis_uri).map_err(|e| anyhow!("parse failed err={}", e))?;
+        if config.mode != RedisMode::Standalone {
+            return Err(anyhow!(
+                "conn builder only supports standalone uris mode={:?}",
+                config.mode
+            ));
+        }
+        let (host, port) = config.nodes[0].clone();
+        config.pubsub_conn_builder(host, port)
+    }
 
-        let port = match parsed_url.port() {
-            Some(port) => port,
-            None => return Err(anyhow::anyhow!("parse port failed")),
-        };
+    /// raw pubsub connection, subscribe through `subscribe_peers` and the like instead
+    /// since a second subscription of a channel on it replaces the first one
+    pub fn get_pubsub_conn(self: Arc<Self>) -> PubsubConnection {
+        self.pubsub.pubsub_conn()
+    }
 
-        Ok(RedisUri {
-            is_tls,
-            password,
-            host,
-            port,
-        })
+    /// subscribe to peer changes published by `publish_peer` for the given masternode
+    pub async fn subscribe_peers(
+        self: Arc<Self>,
+        masternode_id: String,
+    ) -> Result<Subscription<PeerChanged>> {
+        self.pubsub
+            .clone()
+            .subscribe_json(self.namespaced(DPNRedisKey::get_peers_chan(masternode_id)))
+            .await
     }
 
-    pub fn get_redis_conn_builder_from_uri(redis_uri: &str) -> Result<ConnectionBuilder> {
-        let redis_info =
-            Self::parse_redis_uri(redis_uri).map_err(|e| anyhow!("parse failed err={}", e))?;
+    /// subscribe to proxy acc changes published by `publish_proxy_acc`
+    pub async fn subscribe_proxy_accs(
+        self: Arc<Self>,
+    ) -> Result<Subscription<VersionedProxyAccChanged>> {
+        self.pubsub
+            .clone()
+            .subscribe_json(self.namespaced(DPNRedisKey::get_proxy_acc_versioned_chan()))
+            .await
+    }
 
-        let mut connection_builder: ConnectionBuilder =
-            ConnectionBuilder::new(redis_info.host, redis_info.port)
-                .map_err(|e| anyhow!("connection build create failed err={}", e))?;
+    /// subscribe to peer prices published by `publish_peer_price`
+    pub async fn subscribe_prices(self: Arc<Self>) -> Result<Subscription<UserBandwidthPrice>> {
+        self.pubsub
+            .clone()
+            .subscribe_json(self.namespaced(DPNRedisKey::get_price_chan()))
+            .await
+    }
 
-        if redis_info.is_tls {
-            connection_builder.tls();
+    /// returns a handle on the shared multiplexed connection,
+    /// cloning it is cheap and all clones pipeline over the same socket
+    pub fn get_async_conn(self: Arc<Self>) -> RedisConn {
+        self.conn.clone()
+    }
+
+    /// starts a pipeline, its commands are sent together but may interleave with others
+    pub fn batch(&self) -> RedisBatch {
+        RedisBatch::new(self.namespace.clone(), false)
+    }
+
+    /// starts a MULTI/EXEC transaction, its commands are applied all at once or not at all.
+    /// in cluster mode all keys of a transaction must live in one slot
+    pub fn transaction(&self) -> RedisBatch {
+        RedisBatch::new(self.namespace.clone(), true)
+    }
+
+    /// runs a batch and ignores the replies
+    pub async fn exec(self: Arc<Self>, batch: RedisBatch) -> Result<(), RedisServiceError> {
+        if batch.is_empty() {
+            return Ok(());
         }
+        let mut conn = self.conn.clone();
+        batch
+            .pipe()
+            .query_async::<_, ()>(&mut conn)
+            .await
+            .map_err(|e| RedisServiceError::redis("batch", e))
+    }
+
+    /// runs a batch and decodes the replies of its reads as a tuple, in order
+    pub async fn query<T: FromRedisValue>(
+        self: Arc<Self>,
+        batch: RedisBatch,
+    ) -> Result<T, RedisServiceError> {
+        let mut conn = self.conn.clone();
+        batch
+            .pipe()
+            .query_async::<_, T>(&mut conn)
+            .await
+            .map_err(|e| RedisServiceError::redis("batch", e))
+    }
 
-        if let Some(redis_password) = redis_info.password {
-            connection_builder.password(redis_password);
+    /// applies the mutations and publishes the message in one transaction,
+    /// subscribers never see a message whose change is not stored and vice versa.
+    /// `mutations` must be started by `transaction`
+    pub async fn mutate_and_publish<T: Serialize>(
+        self: Arc<Self>,
+        mut mutations: RedisBatch,
+        chan_name: String,
+        msg: &T,
+    ) -> Result<(), RedisServiceError> {
+        if !mutations.is_atomic() {
+            return Err(RedisServiceError::InvalidArgument(format!(
+                "mutate_and_publish needs a transaction chan_name={}",
+                chan_name
+            )));
         }
+        mutations.publish(chan_name, msg);
+        self.exec(mutations).await
+    }
 
-        Ok(connection_builder)
+    pub async fn hset_many<T: Serialize>(
+        self: Arc<Self>,
+        key: String,
+        items: Vec<(String, T)>,
+    ) -> Result<(), RedisServiceError> {
+        let mut batch = self.batch();
+        batch.hset_many(key, items);
+        self.exec(batch).await
     }
 
-    pub fn get_pubsub_conn(self: Arc<Self>) -> PubsubConnection {
-        self.pubsub_con.clone()
+    /// returns the values of the fields in order, `None` for a missing field
+    pub async fn hget_many<T: DeserializeOwned>(
+        self: Arc<Self>,
+        key: String,
+        fields: Vec<String>,
+    ) -> Result<Vec<Option<T>>, RedisServiceError> {
+        if fields.is_empty() {
+            return Ok(vec![]);
+        }
+        let key = self.namespaced(key);
+        let mut conn = self.conn.clone();
+        // HMGET always replies with a list, HGET is used for a single field
+        let result: Vec<Option<String>> = redis::cmd("HMGET")
+            .arg(key.clone())
+            .arg(fields)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| RedisServiceError::redis(format!("hmget key={}", key), e))?;
+        result
+            .into_iter()
+            .map(|obj_str| match obj_str {
+                Some(obj_str) => serde_json::from_str::<T>(&obj_str)
+                    .map(Some)
+                    .map_err(|e| RedisServiceError::decode(key.clone(), e)),
+                None => Ok(None),
+            })
+            .collect()
     }
 
-    pub fn hset<T>(self: Arc<Self>, key: String, field: String, obj: T) -> Result<(), Error>
+    pub async fn hset<T>(
+        self: Arc<Self>,
+        key: String,
+        field: String,
+        obj: T,
+    ) -> Result<(), RedisServiceError>
     where
-        T: Serialize,
+        T: Serialize + Send,
     {
-        let mut conn = self
-            .client
-            .get_connection()
-            .map_err(|e| anyhow!("cannot get connection err={}", e))?;
-        match conn.hset::<String, String, String, usize>(
-            key,
-            field,
-            serde_json::to_string(&obj).unwrap(),
-        ) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(anyhow!("redis failed to insert err={}", e)),
-        }
+        let key = self.namespaced(key);
+        let mut conn = self.conn.clone();
+        conn.hset::<&str, &str, String, usize>(&key, &field, serde_json::to_string(&obj).unwrap())
+            .await
+            .map_err(|e| RedisServiceError::redis(format!("hset key={}:{}", key, field), e))?;
+        Ok(())
     }
 
-    pub fn hget<T>(self: Arc<Self>, key: String, field: String) -> Result<T, Error>
+    /// returns `RedisServiceError::NotFound` when the field is not in the hash
+    pub async fn hget<T>(
+        self: Arc<Self>,
+        key: String,
+        field: String,
+    ) -> Result<T, RedisServiceError>
     where
         T: Clone + DeserializeOwned,
     {
-        let mut conn = self
-            .client
-            .get_connection()
-            .map_err(|e| anyhow!("cannot get connection err={}", e))?;
-        let obj_str: String = conn
-            .hget(key.clone(), field.clone())
-            .map_err(|e| anyhow!("redis cannot get key={}:{} err={}", key, field, e))?;
-        let t = serde_json::from_str::<T>(&obj_str)
-            .map_err(|e| anyhow!("redis failed to decode err={}", e))?;
-        Ok(t)
-    }
-
-    pub fn hgetall<T>(self: Arc<Self>, key: String) -> Result<Vec<(String, T)>, Error>
+        let key = self.namespaced(key);
+        let mut conn = self.conn.clone();
+        let obj_str: Option<String> = conn
+            .hget(&key, &field)
+            .await
+            .map_err(|e| RedisServiceError::redis(format!("hget key={}:{}", key, field), e))?;
+        let key = format!("{}:{}", key, field);
+        let obj_str = obj_str.ok_or_else(|| RedisServiceError::NotFound { key: key.clone() })?;
+        serde_json::from_str::<T>(&obj_str).map_err(|e| RedisServiceError::decode(key, e))
+    }
+
+    pub async fn hgetall<T>(
+        self: Arc<Self>,
+        key: String,
+    ) -> Result<Vec<(String, T)>, RedisServiceError>
     where
         T: Clone + DeserializeOwned,
     {
-        let mut conn = self
-            .client
-            .get_connection()
-            .map_err(|e| anyhow!("cannot get connection err={}", e))?;
+        let key = self.namespaced(key);
+        let mut conn = self.conn.clone();
         let result: HashMap<String, String> = conn
             .hgetall(key.clone())
-            .map_err(|e| anyhow!("redis cannot get key={} err={}", key, e))?;
+            .await
+            .map_err(|e| RedisServiceError::redis(format!("hgetall key={}", key), e))?;
         let mut rs: Vec<(String, T)> = vec![];
-        for (key, obj_str) in result.iter() {
-            let proxy_acc = serde_json::from_str::<T>(&obj_str)
-                .map_err(|e| anyhow!("redis failed to decode err={}", e))?;
-            rs.push((key.clone(), proxy_acc.clone()));
+        for (field, obj_str) in result.iter() {
+            let obj = serde_json::from_str::<T>(obj_str)
+                .map_err(|e| RedisServiceError::decode(format!("{}:{}", key, field), e))?;
+            rs.push((field.clone(), obj));
         }
         Ok(rs)
     }
 
-    pub fn hdel(self: Arc<Self>, key: String, field: String) -> Result<(), Error> {
-        let mut conn = self
-            .client
-            .get_connection()
-            .map_err(|e| anyhow!("cannot get connection err={}", e))?;
-        conn.hdel(key.clone(), field.clone())
-            .map_err(|e| anyhow!("redis cannot hdel key={} field={} err={}", key, field, e))?;
-        Ok(())
+    pub async fn hdel(
+        self: Arc<Self>,
+        key: String,
+        field: String,
+    ) -> Result<(), RedisServiceError> {
+        let key = self.namespaced(key);
+        let mut conn = self.conn.clone();
+        conn.hdel::<_, _, ()>(key.clone(), field.clone())
+            .await
+            .map_err(|e| RedisServiceError::redis(format!("hdel key={}:{}", key, field), e))
     }
 
-    pub fn zadd(self: Arc<Self>, key: String, score: u32, value: u32) -> Result<(), Error> {
-        let mut conn = self
-            .client
-            .get_connection()
-            .map_err(|e| anyhow!("cannot get connection err={}", e))?;
-        match conn.zadd::<String, u32, u32, ()>(key, value, score) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(anyhow!(
-                "redis failed to insert peer into peer queue err={}",
-                e
-            )),
-        }
+    pub async fn zadd(
+        self: Arc<Self>,
+        key: String,
+        score: u32,
+        value: String,
+    ) -> Result<(), RedisServiceError> {
+        let key = self.namespaced(key);
+        let mut conn = self.conn.clone();
+        conn.zadd::<&str, u32, String, ()>(&key, value, score)
+            .await
+            .map_err(|e| RedisServiceError::redis(format!("zadd key={}", key), e))
     }
 
-    pub fn zrem(self: Arc<Self>, key: String, value: u32) -> Result<(), anyhow::Error> {
-        let mut conn = self
-            .client
-            .get_connection()
-            .map_err(|e| anyhow!("cannot get connection err={}", e))?;
-
-        match conn.zrem::<String, u32, usize>(key, value) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(anyhow!(
-                "redis failed to remove peer in peer queue err={}",
-                e
-            )),
-        }
+    pub async fn zrem(
+        self: Arc<Self>,
+        key: String,
+        value: String,
+    ) -> Result<(), RedisServiceError> {
+        let key = self.namespaced(key);
+        let mut conn = self.conn.clone();
+        conn.zrem::<&str, String, usize>(&key, value)
+            .await
+            .map_err(|e| RedisServiceError::redis(format!("zrem key={}", key), e))?;
+        Ok(())
     }
 
-    pub fn zsetall(self: Arc<Self>, key: String, score: u32) -> Result<(), anyhow::Error> {
-        let mut conn = self
-            .client
-            .get_connection()
-            .map_err(|e| anyhow!("cannot get connection err={}", e))?;
-
-        let elements: Vec<(u32, u32)> = conn
-            .zrange_withscores(key.clone(), 0, -1)
-            .map_err(|e| anyhow!("redis failed to get sorted set err={}", e))?;
-
-        for (value, _) in elements {
-            conn.zadd::<String, u32, u32, ()>(key.clone(), value, score)
-                .map_err(|e| anyhow!("redis failed to set scores err={}", e))?;
-        }
+    pub async fn zsetall(
+        self: Arc<Self>,
+        key: String,
+        score: u32,
+    ) -> Result<(), RedisServiceError> {
+        let mut conn = self.conn.clone();
+        let elements: Vec<(String, u32)> = conn
+            .zrange_withscores(self.namespaced(key.clone()), 0, -1)
+            .await
+            .map_err(|e| RedisServiceError::redis(format!("zrange key={}", key), e))?;
 
-        Ok(())
+        let mut batch = self.batch();
+        batch.zupdate_many(
+            key,
+            elements
+                .into_iter()
+                .map(|(value, _)| (score, value))
+                .collect(),
+        );
+        self.exec(batch).await
     }
 
-    pub fn zgetall(self: Arc<Self>, key: String) -> Result<Vec<(u32, u32)>, Error> {
-        let mut conn = self
-            .client
-            .get_connection()
-            .map_err(|e| anyhow!("cannot get connection err={}", e))?;
+    pub async fn zgetall(
+        self: Arc<Self>,
+        key: String,
+    ) -> Result<Vec<(String, u32)>, RedisServiceError> {
+        let key = self.namespaced(key);
+        let mut conn = self.conn.clone();
 
-        let elements: Vec<(u32, u32)> = conn
+        let mut result: Vec<(String, u32)> = conn
             .zrange_withscores(key.clone(), 0, -1)
-            .map_err(|e| anyhow!("redis failed to get peer queue err={}", e))?;
-
-        let mut result: Vec<(u32, u32)> = elements
-            .into_iter()
-            .map(|(value, score)| (value, score))
-            .collect();
+            .await
+            .map_err(|e| RedisServiceError::redis(format!("zrange key={}", key), e))?;
 
         result.sort_by_key(|(_value, score)| *score);
 
@@ -229,220 +404,829 @@ impl RedisService {
     }
 
     /// this function is used to delete data of given key
-    pub fn del(self: Arc<Self>, key: String) -> Result<(), Error> {
-        let mut conn = self
-            .client
-            .get_connection()
-            .map_err(|e| anyhow!("cannot get connection err={}", e))?;
-
-        conn.del(key.clone())
-            .map_err(|e| anyhow!("redis failed to delete key={} err={}", key, e))
-    }
-
-    pub async fn publish(self: Arc<Self>, chan_name: String, obj_str: String) -> Result<(), Error> {
-        let mut conn = self
-            .client
-            .get_connection()
-            .map_err(|e| anyhow!("cannot get connection err={}", e))?;
-        conn.publish(&chan_name, &obj_str)?;
-        Ok(())
+    pub async fn del(self: Arc<Self>, key: String) -> Result<(), RedisServiceError> {
+        let key = self.namespaced(key);
+        let mut conn = self.conn.clone();
+
+        conn.del::<_, ()>(key.clone())
+            .await
+            .map_err(|e| RedisServiceError::redis(format!("del key={}", key), e))
     }
 
+    pub async fn publish(
+        self: Arc<Self>,
+        chan_name: String,
+        obj_str: String,
+    ) -> Result<(), RedisServiceError> {
+        let chan_name = self.namespaced(chan_name);
+        let mut conn = self.conn.clone();
+        conn.publish::<_, _, ()>(&chan_name, &obj_str)
+            .await
+            .map_err(|e| RedisServiceError::redis(format!("publish chan={}", chan_name), e))
+    }
+
+    /// returns a dedicated blocking connection,
+    /// prefer `get_async_conn` on the tokio runtime, not available in cluster mode
     pub async fn get_conn(self: Arc<Self>) -> RedisResult<Connection> {
-        self.client.get_connection()
+        self.config.get_conn()
     }
 
     /// remove all peers in redis cache
     /// it must be called when shutting down masternode
-    pub async fn remove_all_peers(self: Arc<Self>, masternode_id: String) -> anyhow::Result<()> {
-        let (k, _) = DPNRedisKey::get_peers_kf(masternode_id.clone(), 0);
-        let peers = self
-            .clone()
-            .hgetall::<PeerChangedInfo>(k.clone())
-            .map_err(|e| anyhow!("redis get peers failed err={}", e))?;
-
-        for (_, change) in peers {
-            // publish peer to redis
-            let change = PeerChanged::Disconnected(PeerChangedInfo {
-                uuid: change.uuid.clone(),
-                login_session_id: change.login_session_id.clone(),
-                ip_u32: change.ip_u32,
-            });
-
-            if let Err(e) = self
-                .clone()
-                .publish(
-                    DPNRedisKey::get_peers_chan(masternode_id.clone()),
-                    serde_json::to_string(&change).unwrap(),
-                )
-                .await
-            {
-                return Err(anyhow!(
-                    "redis peer status publish failed status={:?} err={}",
-                    change,
-                    e
-                ));
-            }
-        }
+    ///
+    /// the disconnections are published and the peers removed in one transaction
+    pub async fn remove_all_peers(
+        self: Arc<Self>,
+        masternode_id: String,
+    ) -> Result<(), RedisServiceError> {
+        let k = DPNRedisKey::get_peers_k(masternode_id.clone());
+        let peers = self.clone().hgetall::<PeerChangedInfo>(k.clone()).await?;
 
-        self.clone()
-            .del(k)
-            .map_err(|e| anyhow!("failed to remove peers from redis err={}", e))
+        let mut tx = self.transaction();
+        for (_, info) in peers {
+            tx.publish(
+                DPNRedisKey::get_peers_chan(masternode_id.clone()),
+                &PeerChanged::Disconnected(info),
+            );
+        }
+        tx.del(k);
+        self.exec(tx).await
     }
 
+    /// stores the peer change and publishes it in one transaction
     pub async fn publish_peer(
         self: Arc<Self>,
         masternode_id: String,
         status: PeerChanged,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), RedisServiceError> {
+        let mut tx = self.transaction();
         match status.clone() {
             PeerChanged::Connected(info) => {
                 // add peer to redis hash
-                let (k, f) = DPNRedisKey::get_peers_kf(masternode_id.clone(), info.ip_u32);
-                if let Err(e) = self.clone().hset(k, f, info.clone()) {
-                    return Err(anyhow!("redis peer add failed err={}", e));
-                }
+                let (k, f) = DPNRedisKey::get_peers_kf(masternode_id.clone(), info.ip);
+                tx.hset(k, f, &info);
             }
             PeerChanged::Disconnected(info) => {
                 // remove peer from redis hash
-                let (k, f) = DPNRedisKey::get_peers_kf(masternode_id.clone(), info.ip_u32);
-                if let Err(e) = self.clone().hdel(k, f) {
-                    return Err(anyhow!("redis peer removal failed err={}", e));
-                }
+                let (k, f) = DPNRedisKey::get_peers_kf(masternode_id.clone(), info.ip);
+                tx.hdel(k, f);
             }
         };
 
-        if let Err(e) = self
-            .clone()
-            .publish(
-                DPNRedisKey::get_peers_chan(masternode_id.clone()),
-                serde_json::to_string(&status).unwrap(),
-            )
+        self.mutate_and_publish(tx, DPNRedisKey::get_peers_chan(masternode_id), &status)
             .await
-        {
-            return Err(anyhow!(
-                "redis peer status publish failed status={:?} err={}",
-                status,
-                e
-            ));
-        }
-
-        Ok(())
     }
 
-    pub async fn get_peers(self: Arc<Self>, masternode_id: String) -> Result<Vec<PeerChangedInfo>> {
-        let (k, _) = DPNRedisKey::get_peers_kf(masternode_id, 0);
-        let peers = self
-            .clone()
-            .hgetall::<PeerChangedInfo>(k)
-            .map_err(|e| anyhow!("redis get peers failed err={}", e))?;
+    pub async fn get_peers(
+        self: Arc<Self>,
+        masternode_id: String,
+    ) -> Result<Vec<PeerChangedInfo>, RedisServiceError> {
+        let k = DPNRedisKey::get_peers_k(masternode_id);
+        let peers = self.clone().hgetall::<PeerChangedInfo>(k).await?;
         Ok(peers
             .iter()
             .map(|(_, peer_info)| peer_info.clone())
             .collect())
     }
 
+    /// leases the least used peer of the masternode peer queue and bumps its score by one,
+    /// queue entries whose peer is no longer in the peers hash are dropped on the way.
+    /// every successful lease must be given back with `release_peer`
+    pub async fn lease_peer(
+        self: Arc<Self>,
+        masternode_id: String,
+    ) -> Result<Option<(PeerIp, PeerChangedInfo)>, RedisServiceError> {
+        let peers_k = DPNRedisKey::get_peers_k(masternode_id.clone());
+        let peers_k = self.namespaced(peers_k);
+        let mut conn = self.conn.clone();
+        let leased: Option<(String, String)> = redis::Script::new(LEASE_PEER_SCRIPT)
+            .key(self.namespaced(DPNRedisKey::get_peer_queue_k(masternode_id)))
+            .key(peers_k.clone())
+            .invoke_async(&mut conn)
+            .await
+            .map_err(|e| RedisServiceError::redis("lease peer", e))?;
+
+        match leased {
+            Some((ip, info_str)) => {
+                let info = serde_json::from_str::<PeerChangedInfo>(&info_str)
+                    .map_err(|e| RedisServiceError::decode(format!("{}:{}", peers_k, ip), e))?;
+                Ok(Some((info.ip, info)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// gives back a lease taken with `lease_peer`,
+    /// does nothing if the peer has left the queue in the meantime
+    pub async fn release_peer(
+        self: Arc<Self>,
+        masternode_id: String,
+        ip: PeerIp,
+    ) -> Result<(), RedisServiceError> {
+        let mut conn = self.conn.clone();
+        redis::Script::new(RELEASE_PEER_SCRIPT)
+            .key(self.namespaced(DPNRedisKey::get_peer_queue_k(masternode_id)))
+            .arg(ip.to_string())
+            .invoke_async::<_, ()>(&mut conn)
+            .await
+            .map_err(|e| RedisServiceError::redis(format!("release peer ip={}", ip), e))
+    }
+
+    /// rewrites the peers hash and the peer queue of a masternode from the `u32` ip form
+    /// used before ipv6 support to the textual form of `PeerIp`, and moves the entries of
+    /// `get_legacy_peer_queue_k` to the current queue. returns the number of entries
+    /// rewritten. the rewrite is applied in one transaction from a snapshot,
+    /// run it before the masternode accepts peers so no change slips in between
+    pub async fn migrate_legacy_peer_ips(
+        self: Arc<Self>,
+        masternode_id: String,
+    ) -> Result<usize, RedisServiceError> {
+        let peers_k = DPNRedisKey::get_peers_k(masternode_id.clone());
+        let queue_k = DPNRedisKey::get_peer_queue_k(masternode_id.clone());
+        let legacy_queue_k = DPNRedisKey::get_legacy_peer_queue_k(masternode_id);
+        let peers = self
+            .clone()
+            .hgetall::<PeerChangedInfo>(peers_k.clone())
+            .await?;
+        let queue = self.clone().zgetall(queue_k.clone()).await?;
+        let legacy_queue = self.clone().zgetall(legacy_queue_k.clone()).await?;
+        let as_ip = |member: &String| {
+            PeerIp::from_str(member)
+                .map(|ip| ip.to_string())
+                .unwrap_or(member.clone())
+        };
+        let mut queued: HashSet<String> = queue.iter().map(|(m, _)| as_ip(m)).collect();
+
+        let mut tx = self.transaction();
+        let mut migrated = 0;
+        for (field, info) in peers {
+            if PeerIp::is_legacy(&field) {
+                tx.hdel(peers_k.clone(), field)
+                    .hset(peers_k.clone(), info.ip.to_string(), &info);
+                migrated += 1;
+            }
+        }
+        for (member, score) in queue {
+            if PeerIp::is_legacy(&member) {
+                let ip = PeerIp::from_str(&member).unwrap();
+                tx.zrem(queue_k.clone(), member)
+                    .zadd(queue_k.clone(), score, ip.to_string());
+                migrated += 1;
+            }
+        }
+        // the legacy queue is in another cluster slot, its entries are copied in the
+        // transaction and the key dropped after it. an ip already queued keeps its score
+        for (member, score) in legacy_queue.iter() {
+            let ip = as_ip(member);
+            if queued.insert(ip.clone()) {
+                tx.zadd(queue_k.clone(), *score, ip);
+                migrated += 1;
+            }
+        }
+        self.clone().exec(tx).await?;
+        if !legacy_queue.is_empty() {
+            self.del(legacy_queue_k).await?;
+        }
+        Ok(migrated)
+    }
+
     pub async fn publish_peer_price(
         self: Arc<Self>,
         price: UserBandwidthPrice,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), RedisServiceError> {
         let (k, f) = DPNRedisKey::get_price_kf(price.user_addr.clone());
-        self.clone()
-            .hset(k, f, price.clone())
-            .map_err(|e| anyhow!("redis set peer price failed err={}", e))?;
-
-        self.clone()
-            .publish(
-                DPNRedisKey::get_price_chan(),
-                serde_json::to_string(&price).unwrap(),
-            )
+        let mut tx = self.transaction();
+        tx.hset(k, f, &price);
+        self.mutate_and_publish(tx, DPNRedisKey::get_price_chan(), &price)
             .await
-            .map_err(|e| {
-                anyhow!(
-                    "redis peer status publish failed price={:?} err={}",
-                    price,
-                    e
-                )
-            })?;
-        Ok(())
     }
 
-    pub async fn get_peers_price(self: Arc<Self>) -> Result<Vec<UserBandwidthPrice>> {
+    pub async fn get_peers_price(
+        self: Arc<Self>,
+    ) -> Result<Vec<UserBandwidthPrice>, RedisServiceError> {
         let (k, _) = DPNRedisKey::get_price_kf("".to_string());
-        let peers = self
-            .clone()
-            .hgetall::<UserBandwidthPrice>(k)
-            .map_err(|e| anyhow!("redis get peers price failed err={}", e))?;
+        let peers = self.clone().hgetall::<UserBandwidthPrice>(k).await?;
         Ok(peers
             .iter()
             .map(|(_, peer_info)| peer_info.clone())
             .collect())
     }
 
-    pub async fn get_proxy_accs(self: Arc<Self>) -> Result<Vec<ProxyAccData>> {
+    /// returns the balance of a client, a client without balance has zero
+    pub async fn get_balance(
+        self: Arc<Self>,
+        user_addr: String,
+    ) -> Result<UserBalance, RedisServiceError> {
+        let (k, f) = DPNRedisKey::get_balance_kf(user_addr.clone());
+        let k = self.namespaced(k);
+        let mut conn = self.conn.clone();
+        let balance: Option<i64> = conn
+            .hget(k.clone(), f.clone())
+            .await
+            .map_err(|e| RedisServiceError::redis(format!("hget key={}:{}", k, f), e))?;
+        Ok(UserBalance {
+            user_addr,
+            balance: balance.unwrap_or_default(),
+        })
+    }
+
+    /// adds amount to the balance of a client and returns the new balance
+    pub async fn credit(
+        self: Arc<Self>,
+        user_addr: String,
+        amount: i64,
+    ) -> Result<UserBalance, RedisServiceError> {
+        if amount < 0 {
+            return Err(RedisServiceError::InvalidArgument(format!(
+                "credit amount must not be negative amount={}",
+                amount
+            )));
+        }
+        let (k, f) = DPNRedisKey::get_balance_kf(user_addr.clone());
+        let k = self.namespaced(k);
+        let mut conn = self.conn.clone();
+        let balance: i64 = conn.hincr(k, f, amount).await.map_err(|e| {
+            RedisServiceError::redis(format!("credit balance user_addr={}", user_addr), e)
+        })?;
+        Ok(UserBalance { user_addr, balance })
+    }
+
+    /// subtracts amount from the balance of a client only if the balance covers it,
+    /// the check and the subtraction run as one script so concurrent debits
+    /// from several masternodes can never bring a balance below zero
+    pub async fn debit_if_sufficient(
+        self: Arc<Self>,
+        user_addr: String,
+        amount: i64,
+    ) -> Result<DebitOutcome, RedisServiceError> {
+        if amount < 0 {
+            return Err(RedisServiceError::InvalidArgument(format!(
+                "debit amount must not be negative amount={}",
+                amount
+            )));
+        }
+        let (k, f) = DPNRedisKey::get_balance_kf(user_addr.clone());
+        let k = self.namespaced(k);
+        let mut conn = self.conn.clone();
+        let (debited, balance): (bool, i64) = redis::Script::new(DEBIT_IF_SUFFICIENT_SCRIPT)
+            .key(k)
+            .arg(f)
+            .arg(amount)
+            .invoke_async(&mut conn)
+            .await
+            .map_err(|e| {
+                RedisServiceError::redis(format!("debit balance user_addr={}", user_addr), e)
+            })?;
+
+        let balance = UserBalance { user_addr, balance };
+        match debited {
+            true => Ok(DebitOutcome::Debited(balance)),
+            false => Ok(DebitOutcome::Insufficient(balance)),
+        }
+    }
+
+    pub async fn get_proxy_accs(self: Arc<Self>) -> Result<Vec<ProxyAccData>, RedisServiceError> {
         let (k, _) = DPNRedisKey::get_proxy_acc_kf("".to_string());
-        let proxy_accs = self
-            .clone()
-            .hgetall::<ProxyAccData>(k)
-            .map_err(|e| anyhow!("redis get proxy accs failed err={}", e))?;
+        let proxy_accs = self.clone().hgetall::<ProxyAccData>(k).await?;
         Ok(proxy_accs.iter().map(|(_, pad)| pad.clone()).collect())
     }
 
+    /// returns the proxy accs together with the version they were read at,
+    /// both are read in one transaction so no change can slip in between
+    pub async fn get_proxy_accs_snapshot(
+        self: Arc<Self>,
+    ) -> Result<(u64, Vec<ProxyAccData>), RedisServiceError> {
+        let (k, _) = DPNRedisKey::get_proxy_acc_kf("".to_string());
+        let mut tx = self.transaction();
+        tx.get(DPNRedisKey::get_proxy_acc_version_k())
+            .hgetall(k.clone());
+        let (version, result): (Option<u64>, HashMap<String, String>) =
+            self.clone().query(tx).await?;
+
+        let mut proxy_accs: Vec<ProxyAccData> = vec![];
+        for (id, obj_str) in result.iter() {
+            let pad = serde_json::from_str::<ProxyAccData>(obj_str).map_err(|e| {
+                RedisServiceError::decode(format!("{}:{}", self.namespaced(k.clone()), id), e)
+            })?;
+            proxy_accs.push(pad);
+        }
+        Ok((version.unwrap_or_default(), proxy_accs))
+    }
+
+    /// returns the version of the latest published proxy acc change
+    pub async fn get_proxy_acc_version(self: Arc<Self>) -> Result<u64, RedisServiceError> {
+        let k = self.namespaced(DPNRedisKey::get_proxy_acc_version_k());
+        let mut conn = self.conn.clone();
+        let version: Option<u64> = conn
+            .get(&k)
+            .await
+            .map_err(|e| RedisServiceError::redis(format!("get key={}", k), e))?;
+        Ok(version.unwrap_or_default())
+    }
+
     /// remove all proxy accs in redis cache
-    /// it must be called when admin started
+    /// it must be called when admin started, by the leader only when several admins run
+    /// (see `LeaderElection` and `reload_proxy_accs_fenced`), otherwise their reloads interleave
     /// after removal, proxy accs are loaded from db and added to redis
-    pub async fn remove_all_proxy_accs(self: Arc<Self>) -> anyhow::Result<()> {
-        let (k, _) = DPNRedisKey::get_proxy_acc_kf("".to_owned());
+    ///
+    /// `RefreshAll` is published with the bumped version in the same script
+    pub async fn remove_all_proxy_accs(self: Arc<Self>) -> Result<(), RedisServiceError> {
+        self.apply_proxy_acc(
+            "clear",
+            "".to_owned(),
+            "".to_owned(),
+            ProxyAccChanged::RefreshAll(),
+            None,
+        )
+        .await
+    }
+
+    /// `remove_all_proxy_accs` for the leader, fails with `StaleFencingToken` once
+    /// a later leader removed them with a higher fencing token
+    pub async fn remove_all_proxy_accs_fenced(
+        self: Arc<Self>,
+        fencing_token: u64,
+    ) -> Result<(), RedisServiceError> {
+        self.apply_proxy_acc(
+            "clear",
+            "".to_owned(),
+            "".to_owned(),
+            ProxyAccChanged::RefreshAll(),
+            Some(fencing_token),
+        )
+        .await
+    }
+
+    /// replaces every proxy acc by `proxy_accs` as the leader, each write is fenced so
+    /// a deposed leader stops at its first write after a later leader wrote
+    pub async fn reload_proxy_accs_fenced(
+        self: Arc<Self>,
+        fencing_token: u64,
+        proxy_accs: Vec<ProxyAccData>,
+    ) -> Result<(), RedisServiceError> {
         self.clone()
-            .del(k)
-            .map_err(|e| anyhow!("failed to remove peers from redis err={}", e))
+            .remove_all_proxy_accs_fenced(fencing_token)
+            .await?;
+        for pad in proxy_accs {
+            self.clone()
+                .publish_proxy_acc_fenced(ProxyAccChanged::Created(pad), fencing_token)
+                .await?;
+        }
+        Ok(())
     }
 
+    /// applies the change to the proxy acc hash and publishes it with a new version,
+    /// the increment, the write and the publish run as one script so subscribers
+    /// always receive changes in version order. a transaction cannot be used here
+    /// as the published message carries the incremented version
     pub async fn publish_proxy_acc(
         self: Arc<Self>,
         proxy_acc_changed: ProxyAccChanged,
-    ) -> anyhow::Result<()> {
-        match proxy_acc_changed.clone() {
-            ProxyAccChanged::Created(pad) => {
-                let (k, f) = DPNRedisKey::get_proxy_acc_kf(pad.id.clone());
-                self.clone()
-                    .hset(k, f, pad.clone())
-                    .map_err(|e| anyhow!("{}", e))?;
-            }
-            ProxyAccChanged::Updated(pad) => {
-                let (k, f) = DPNRedisKey::get_proxy_acc_kf(pad.id.clone());
-                self.clone()
-                    .hset(k, f, pad.clone())
-                    .map_err(|e| anyhow!("{}", e))?;
-            }
-            ProxyAccChanged::Deleted(id) => {
-                let (k, f) = DPNRedisKey::get_proxy_acc_kf(id.clone());
-                self.clone().hdel(k, f).map_err(|e| anyhow!("{}", e))?;
+    ) -> Result<(), RedisServiceError> {
+        self.publish_proxy_acc_with(proxy_acc_changed, None).await
+    }
+
+    /// `publish_proxy_acc` for the leader, see `remove_all_proxy_accs_fenced`
+    pub async fn publish_proxy_acc_fenced(
+        self: Arc<Self>,
+        proxy_acc_changed: ProxyAccChanged,
+        fencing_token: u64,
+    ) -> Result<(), RedisServiceError> {
+        self.publish_proxy_acc_with(proxy_acc_changed, Some(fencing_token))
+            .await
+    }
+
+    async fn publish_proxy_acc_with(
+        self: Arc<Self>,
+        proxy_acc_changed: ProxyAccChanged,
+        fencing_token: Option<u64>,
+    ) -> Result<(), RedisServiceError> {
+        let (op, id, value) = match proxy_acc_changed.clone() {
+            ProxyAccChanged::Created(pad) | ProxyAccChanged::Updated(pad) => {
+                ("set", pad.id.clone(), serde_json::to_string(&pad).unwrap())
             }
-            ProxyAccChanged::RefreshAll() => { /**/ }
-        }
+            ProxyAccChanged::Deleted(id) => ("del", id, "".to_owned()),
+            ProxyAccChanged::RefreshAll() => ("none", "".to_owned(), "".to_owned()),
+        };
+        self.apply_proxy_acc(op, id, value, proxy_acc_changed, fencing_token)
+            .await
+    }
 
-        if let Err(e) = self
+    /// runs `PUBLISH_PROXY_ACC_SCRIPT` for the proxy acc `id`, checking
+    /// `fencing_token` against the write fence of the proxy acc hash when given
+    async fn apply_proxy_acc(
+        self: Arc<Self>,
+        op: &str,
+        id: String,
+        value: String,
+        proxy_acc_changed: ProxyAccChanged,
+        fencing_token: Option<u64>,
+    ) -> Result<(), RedisServiceError> {
+        let (k, f) = DPNRedisKey::get_proxy_acc_kf(id);
+
+        let mut conn = self.conn.clone();
+        redis::Script::new(PUBLISH_PROXY_ACC_SCRIPT)
+            .key(self.namespaced(k.clone()))
+            .key(self.namespaced(DPNRedisKey::get_proxy_acc_version_k()))
+            .key(self.namespaced(DPNRedisKey::get_write_fence_k(k)))
+            .arg(op)
+            .arg(f)
+            .arg(value)
+            .arg(self.namespaced(DPNRedisKey::get_proxy_acc_chan()))
+            .arg(serde_json::to_string(&proxy_acc_changed).unwrap())
+            .arg(self.namespaced(DPNRedisKey::get_proxy_acc_versioned_chan()))
+            .arg(fencing_token.unwrap_or_default())
+            .arg(WRITE_FENCE_TTL.as_millis() as u64)
+            .invoke_async::<_, u64>(&mut conn)
+            .await
+            .map_err(|e| {
+                RedisServiceError::fenced(
+                    format!("publish proxy acc change={:?}", proxy_acc_changed),
+                    fencing_token,
+                    e,
+                )
+            })?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StorageService for RedisService {
+    async fn hset(self: Arc<Self>, key: String, field: String, value: Value) -> Result<()> {
+        Ok(RedisService::hset(self, key, field, value).await?)
+    }
+
+    async fn hget(self: Arc<Self>, key: String, field: String) -> Result<Value> {
+        Ok(RedisService::hget(self, key, field).await?)
+    }
+
+    async fn hgetall(self: Arc<Self>, key: String) -> Result<Vec<(String, Value)>> {
+        Ok(RedisService::hgetall(self, key).await?)
+    }
+
+    async fn hdel(self: Arc<Self>, key: String, field: String) -> Result<()> {
+        Ok(RedisService::hdel(self, key, field).await?)
+    }
+
+    async fn zadd(self: Arc<Self>, key: String, score: u32, value: String) -> Result<()> {
+        Ok(RedisService::zadd(self, key, score, value).await?)
+    }
+
+    async fn zrem(self: Arc<Self>, key: String, value: String) -> Result<()> {
+        Ok(RedisService::zrem(self, key, value).await?)
+    }
+
+    async fn zsetall(self: Arc<Self>, key: String, score: u32) -> Result<()> {
+        Ok(RedisService::zsetall(self, key, score).await?)
+    }
+
+    async fn zgetall(self: Arc<Self>, key: String) -> Result<Vec<(String, u32)>> {
+        Ok(RedisService::zgetall(self, key).await?)
+    }
+
+    async fn del(self: Arc<Self>, key: String) -> Result<()> {
+        Ok(RedisService::del(self, key).await?)
+    }
+
+    async fn publish(self: Arc<Self>, chan_name: String, obj_str: String) -> Result<()> {
+        Ok(RedisService::publish(self, chan_name, obj_str).await?)
+    }
+
+    async fn subscribe(self: Arc<Self>, chan_name: String) -> Result<Subscription<Value>> {
+        self.pubsub
             .clone()
-            .publish(
-                DPNRedisKey::get_proxy_acc_chan(),
-                serde_json::to_string(&proxy_acc_changed).unwrap(),
-            )
+            .subscribe_json(self.namespaced(chan_name))
             .await
-        {
-            return Err(anyhow!(
-                "redis proxy acc publish failed change={:?} err={}",
-                proxy_acc_changed,
-                e
-            ));
-        }
+    }
 
-        Ok(())
+    async fn publish_peer(
+        self: Arc<Self>,
+        masternode_id: String,
+        status: PeerChanged,
+    ) -> Result<()> {
+        Ok(RedisService::publish_peer(self, masternode_id, status).await?)
+    }
+
+    async fn get_peers(self: Arc<Self>, masternode_id: String) -> Result<Vec<PeerChangedInfo>> {
+        Ok(RedisService::get_peers(self, masternode_id).await?)
+    }
+
+    async fn remove_all_peers(self: Arc<Self>, masternode_id: String) -> Result<()> {
+        Ok(RedisService::remove_all_peers(self, masternode_id).await?)
+    }
+
+    async fn subscribe_peers(
+        self: Arc<Self>,
+        masternode_id: String,
+    ) -> Result<Subscription<PeerChanged>> {
+        RedisService::subscribe_peers(self, masternode_id).await
+    }
+
+    async fn lease_peer(
+        self: Arc<Self>,
+        masternode_id: String,
+    ) -> Result<Option<(PeerIp, PeerChangedInfo)>> {
+        Ok(RedisService::lease_peer(self, masternode_id).await?)
+    }
+
+    async fn release_peer(self: Arc<Self>, masternode_id: String, ip: PeerIp) -> Result<()> {
+        Ok(RedisService::release_peer(self, masternode_id, ip).await?)
     }
+
+    async fn get_balance(self: Arc<Self>, user_addr: String) -> Result<UserBalance> {
+        Ok(RedisService::get_balance(self, user_addr).await?)
+    }
+
+    async fn credit(self: Arc<Self>, user_addr: String, amount: i64) -> Result<UserBalance> {
+        Ok(RedisService::credit(self, user_addr, amount).await?)
+    }
+
+    async fn debit_if_sufficient(
+        self: Arc<Self>,
+        user_addr: String,
+        amount: i64,
+    ) -> Result<DebitOutcome> {
+        Ok(RedisService::debit_if_sufficient(self, user_addr, amount).await?)
+    }
+
+    async fn publish_peer_price(self: Arc<Self>, price: UserBandwidthPrice) -> Result<()> {
+        Ok(RedisService::publish_peer_price(self, price).await?)
+    }
+
+    async fn get_peers_price(self: Arc<Self>) -> Result<Vec<UserBandwidthPrice>> {
+        Ok(RedisService::get_peers_price(self).await?)
+    }
+
+    async fn publish_proxy_acc(self: Arc<Self>, proxy_acc_changed: ProxyAccChanged) -> Result<()> {
+        Ok(RedisService::publish_proxy_acc(self, proxy_acc_changed).await?)
+    }
+
+    async fn get_proxy_accs(self: Arc<Self>) -> Result<Vec<ProxyAccData>> {
+        Ok(RedisService::get_proxy_accs(self).await?)
+    }
+
+    async fn get_proxy_accs_snapshot(self: Arc<Self>) -> Result<(u64, Vec<ProxyAccData>)> {
+        Ok(RedisService::get_proxy_accs_snapshot(self).await?)
+    }
+
+    async fn get_proxy_acc_version(self: Arc<Self>) -> Result<u64> {
+        Ok(RedisService::get_proxy_acc_version(self).await?)
+    }
+
+    async fn remove_all_proxy_accs(self: Arc<Self>) -> Result<()> {
+        Ok(RedisService::remove_all_proxy_accs(self).await?)
+    }
+
+    async fn subscribe_proxy_accs(
+        self: Arc<Self>,
+    ) -> Result<Subscription<VersionedProxyAccChanged>> {
+        RedisService::subscribe_proxy_accs(self).await
+    }
+}
+
+/// KEYS[1] balance hash
+/// ARGV[1] user addr, ARGV[2] amount
+/// returns {1, new balance} when debited or {0, current balance} when insufficient
+const DEBIT_IF_SUFFICIENT_SCRIPT: &str = r#"
+local balance = tonumber(redis.call('HGET', KEYS[1], ARGV[1]) or '0')
+if balance < tonumber(ARGV[2]) then
+    return {0, balance}
+end
+return {1, redis.call('HINCRBY', KEYS[1], ARGV[1], -tonumber(ARGV[2]))}
+"#;
+
+/// KEYS[1] peer queue, KEYS[2] peers hash
+/// returns the leased peer as {peer ip, peer info} or nil if no peer is available
+const LEASE_PEER_SCRIPT: &str = r#"
+while true do
+    local head = redis.call('ZRANGE', KEYS[1], 0, 0)
+    if #head == 0 then
+        return false
+    end
+    local info = redis.call('HGET', KEYS[2], head[1])
+    if info then
+        redis.call('ZINCRBY', KEYS[1], 1, head[1])
+        return {head[1], info}
+    end
+    redis.call('ZREM', KEYS[1], head[1])
+end
+"#;
+
+/// KEYS[1] peer queue
+/// ARGV[1] peer ip
+const RELEASE_PEER_SCRIPT: &str = r#"
+local score = redis.call('ZSCORE', KEYS[1], ARGV[1])
+if score and tonumber(score) > 0 then
+    redis.call('ZINCRBY', KEYS[1], -1, ARGV[1])
+end
+return 0
+"#;
+
+/// KEYS[1] proxy acc hash, KEYS[2] proxy acc version, KEYS[3] write fence of the hash
+/// ARGV[1] op (set|del|clear|none), ARGV[2] field, ARGV[3] value, ARGV[4] channel, ARGV[5] change,
+/// ARGV[6] versioned channel, ARGV[7] fencing token or 0 when unguarded, ARGV[8] fence ttl in ms
+/// the change is published as is on the channel and as a `VersionedProxyAccChanged`
+/// on the versioned channel
+const PUBLISH_PROXY_ACC_SCRIPT: &str = r#"
+if ARGV[7] ~= '0' then
+    local highest = tonumber(redis.call('GET', KEYS[3]) or '0')
+    if tonumber(ARGV[7]) < highest then
+        return redis.error_reply('STALE_FENCE highest=' .. highest)
+    end
+    redis.call('SET', KEYS[3], ARGV[7], 'PX', ARGV[8])
+end
+local version = redis.call('INCR', KEYS[2])
+if ARGV[1] == 'set' then
+    redis.call('HSET', KEYS[1], ARGV[2], ARGV[3])
+elseif ARGV[1] == 'del' then
+    redis.call('HDEL', KEYS[1], ARGV[2])
+elseif ARGV[1] == 'clear' then
+    redis.call('DEL', KEYS[1])
+end
+redis.call('PUBLISH', ARGV[4], ARGV[5])
+redis.call('PUBLISH', ARGV[6], '{"version":' .. version .. ',"change":' .. ARGV[5] .. '}')
+return version
+"#;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedisKeyKind {
+    Hash,
+    SortedSet,
+    Set,
+    String,
+    Channel,
+}
+
+/// a group of keys built by the same `DPNRedisKey` fn
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedisKeyFamily {
+    /// name of the `DPNRedisKey` fn building the keys
+    pub name: &'static str,
+    /// glob pattern matching every key of the family, without namespace
+    pub pattern: String,
+    pub kind: RedisKeyKind,
 }
 
 pub struct DPNRedisKey {}
 impl DPNRedisKey {
+    /// prefixes a key or channel with the namespace, an empty namespace leaves it as is.
+    /// a key starting with a hash tag gets the namespace inside the tag, so it keeps
+    /// the slot of the key it was built from by `in_slot_of`
+    pub fn with_namespace(namespace: &str, key: String) -> String {
+        match (namespace.is_empty(), key.strip_prefix('{')) {
+            (true, _) => key,
+            (false, Some(tagged)) => format!("{{{}:{}", namespace, tagged),
+            (false, None) => format!("{}:{}", namespace, key),
+        }
+    }
+
+    /// `{key}suffix`, only the hash tag counts for the cluster slot so scripts and
+    /// transactions can use both keys in cluster mode. `key` must not hold a tag itself
+    fn in_slot_of(key: String, suffix: &str) -> String {
+        format!("{{{}}}{}", key, suffix)
+    }
+
+    /// every key and channel family, keep it in sync when adding a key fn
+    pub fn families() -> Vec<RedisKeyFamily> {
+        let family = |name: &'static str, pattern: String, kind: RedisKeyKind| RedisKeyFamily {
+            name,
+            pattern,
+            kind,
+        };
+        vec![
+            family(
+                "get_geo_k",
+                Self::get_geo_k("*".to_owned(), "*".to_owned()),
+                RedisKeyKind::String,
+            ),
+            family(
+                "get_geo_ip_k",
+                Self::get_geo_ip_k("*".to_owned()),
+                RedisKeyKind::String,
+            ),
+            family(
+                "get_balance_kf",
+                Self::get_balance_kf("".to_owned()).0,
+                RedisKeyKind::Hash,
+            ),
+            family(
+                "get_peer_queue_k",
+                Self::get_peer_queue_k("*".to_owned()),
+                RedisKeyKind::SortedSet,
+            ),
+            family(
+                "get_legacy_peer_queue_k",
+                Self::get_legacy_peer_queue_k("*".to_owned()),
+                RedisKeyKind::SortedSet,
+            ),
+            family(
+                "get_peers_kf",
+                Self::get_peers_k("*".to_owned()),
+                RedisKeyKind::Hash,
+            ),
+            family(
+                "get_masternode_lease_k",
+                Self::get_masternode_lease_k("*".to_owned()),
+                RedisKeyKind::String,
+            ),
+            family(
+                "get_masternode_leases_k",
+                Self::get_masternode_leases_k(),
+                RedisKeyKind::Set,
+            ),
+            family(
+                "get_rate_limit_requests_k",
+                Self::get_rate_limit_requests_k("*".to_owned()),
+                RedisKeyKind::Hash,
+            ),
+            family(
+                "get_rate_limit_streams_k",
+                Self::get_rate_limit_streams_k("*".to_owned()),
+                RedisKeyKind::SortedSet,
+            ),
+            family(
+                "get_rate_limit_bytes_k",
+                Self::get_rate_limit_bytes_k("*".to_owned()),
+                RedisKeyKind::Hash,
+            ),
+            family(
+                "get_lock_k",
+                Self::get_lock_k("*".to_owned()),
+                RedisKeyKind::String,
+            ),
+            family(
+                "get_lock_fence_k",
+                Self::get_lock_fence_k("*".to_owned()),
+                RedisKeyKind::String,
+            ),
+            family(
+                "get_write_fence_k",
+                Self::get_write_fence_k("*".to_owned()),
+                RedisKeyKind::String,
+            ),
+            family(
+                "get_ip_rotation_kf",
+                Self::get_ip_rotation_kf("*".to_owned(), "".to_owned()).0,
+                RedisKeyKind::Hash,
+            ),
+            family(
+                "get_price_kf",
+                Self::get_price_kf("".to_owned()).0,
+                RedisKeyKind::Hash,
+            ),
+            family(
+                "get_proxy_acc_kf",
+                Self::get_proxy_acc_kf("".to_owned()).0,
+                RedisKeyKind::Hash,
+            ),
+            family(
+                "get_proxy_acc_version_k",
+                Self::get_proxy_acc_version_k(),
+                RedisKeyKind::String,
+            ),
+            family(
+                "get_uptime_xp_kf",
+                Self::get_uptime_xp_kf("".to_owned()).0,
+                RedisKeyKind::Hash,
+            ),
+            family(
+                "get_uptime_xp_created_kf",
+                Self::get_uptime_xp_created_kf("".to_owned()).0,
+                RedisKeyKind::Hash,
+            ),
+            family(
+                "get_uptime_xp_pending_kf",
+                Self::get_uptime_xp_pending_kf("".to_owned()).0,
+                RedisKeyKind::Hash,
+            ),
+            family(
+                "get_uptime_xp_credited_k",
+                Self::get_uptime_xp_credited_k("*".to_owned()),
+                RedisKeyKind::String,
+            ),
+            family(
+                "get_peers_chan",
+                Self::get_peers_chan("*".to_owned()),
+                RedisKeyKind::Channel,
+            ),
+            family(
+                "get_proxy_acc_chan",
+                Self::get_proxy_acc_chan(),
+                RedisKeyKind::Channel,
+            ),
+            family(
+                "get_proxy_acc_versioned_chan",
+                Self::get_proxy_acc_versioned_chan(),
+                RedisKeyKind::Channel,
+            ),
+            family(
+                "get_price_chan",
+                Self::get_price_chan(),
+                RedisKeyKind::Channel,
+            ),
+        ]
+    }
+
     pub fn get_geo_kf(masternode_id: String, login_session_id: String) -> (String, String) {
         (
             "peer_geo".to_owned(),
@@ -450,23 +1234,83 @@ impl DPNRedisKey {
         )
     }
 
+    /// geo of a peer session, one key per session so each expires on its own.
+    /// the `peer_geo` hash written before is no longer used and can be deleted
+    pub fn get_geo_k(masternode_id: String, login_session_id: String) -> String {
+        let (k, f) = Self::get_geo_kf(masternode_id, login_session_id);
+        format!("{}#{}", k, f)
+    }
+
+    pub fn get_geo_ip_k(ip_addr: String) -> String {
+        format!("peer_geo_ip#{}", ip_addr)
+    }
+
     pub fn get_balance_kf(user_addr: String) -> (String, String) {
-        (
-            "client_user_balance".to_owned(),
-            format!("{}", user_addr),
-        )
+        ("client_user_balance".to_owned(), format!("{}", user_addr))
     }
 
+    /// in the slot of the peers hash
     pub fn get_peer_queue_k(masternode_id: String) -> String {
+        Self::in_slot_of(Self::get_peers_k(masternode_id), "_queue")
+    }
+
+    /// peer queue key used before the hash tags, emptied by `migrate_legacy_peer_ips`
+    pub fn get_legacy_peer_queue_k(masternode_id: String) -> String {
         format!("peer_queue_ms#{}_", masternode_id)
     }
 
-    pub fn get_peers_kf(masternode_id: String, ip_u32: u32) -> (String, String) {
-        (format!("peers_ms#{}", masternode_id), format!("{}", ip_u32))
+    pub fn get_peers_k(masternode_id: String) -> String {
+        format!("peers_ms#{}", masternode_id)
+    }
+
+    pub fn get_peers_kf(masternode_id: String, ip: PeerIp) -> (String, String) {
+        (Self::get_peers_k(masternode_id), ip.to_string())
     }
 
+    /// in the slot of the peers hash
+    pub fn get_masternode_lease_k(masternode_id: String) -> String {
+        Self::in_slot_of(Self::get_peers_k(masternode_id), "_lease")
+    }
+
+    pub fn get_masternode_leases_k() -> String {
+        "masternode_leases".to_owned()
+    }
+
+    pub fn get_rate_limit_requests_k(proxy_acc_id: String) -> String {
+        format!("rate_limit_requests#{}", proxy_acc_id)
+    }
+
+    pub fn get_rate_limit_streams_k(proxy_acc_id: String) -> String {
+        format!("rate_limit_streams#{}", proxy_acc_id)
+    }
+
+    pub fn get_rate_limit_bytes_k(proxy_acc_id: String) -> String {
+        format!("rate_limit_bytes#{}", proxy_acc_id)
+    }
+
+    pub fn get_lock_k(name: String) -> String {
+        format!("lock#{}", name)
+    }
+
+    /// in the slot of the lock
+    pub fn get_lock_fence_k(name: String) -> String {
+        Self::in_slot_of(Self::get_lock_k(name), "_fence")
+    }
+
+    /// highest fencing token a guarded write to `key` came with, in the slot of `key`
+    pub fn get_write_fence_k(key: String) -> String {
+        Self::in_slot_of(key, "_write_fence")
+    }
+
+    /// one field per sticky session key of the proxy acc
+    pub fn get_ip_rotation_kf(proxy_acc_id: String, sticky_key: String) -> (String, String) {
+        (format!("ip_rotation#{}", proxy_acc_id), sticky_key)
+    }
+
+    /// in the slot of the peers hash, so a peer change and its message fit in one
+    /// transaction in cluster mode
     pub fn get_peers_chan(masternode_id: String) -> String {
-        format!("peers_updated_ms#{}", masternode_id)
+        Self::in_slot_of(Self::get_peers_k(masternode_id), "_updated")
     }
 
     pub fn get_price_kf(peer_addr: String) -> (String, String) {
@@ -477,15 +1321,152 @@ impl DPNRedisKey {
         ("proxy_acc".to_owned(), id)
     }
 
+    /// in the slot of the proxy acc hash
+    pub fn get_proxy_acc_version_k() -> String {
+        Self::in_slot_of(Self::get_proxy_acc_kf("".to_owned()).0, "_version")
+    }
+
+    /// seconds of uptime credited to each user, see `UptimeXpAccumulator`
     pub fn get_uptime_xp_kf(id: String) -> (String, String) {
         ("uptime_xp".to_owned(), id)
     }
 
+    /// unix seconds of the first uptime credited to each user, in the slot of `uptime_xp`
+    pub fn get_uptime_xp_created_kf(user_addr: String) -> (String, String) {
+        (
+            Self::in_slot_of(Self::get_uptime_xp_kf("".to_owned()).0, "_created"),
+            user_addr,
+        )
+    }
+
+    /// unix seconds of the last credit of users credited since the last flush,
+    /// in the slot of `uptime_xp`
+    pub fn get_uptime_xp_pending_kf(user_addr: String) -> (String, String) {
+        (
+            Self::in_slot_of(Self::get_uptime_xp_kf("".to_owned()).0, "_pending"),
+            user_addr,
+        )
+    }
+
+    /// in the slot of `uptime_xp`
+    pub fn get_uptime_xp_credited_k(user_addr: String) -> String {
+        Self::in_slot_of(
+            Self::get_uptime_xp_kf("".to_owned()).0,
+            &format!("_credited#{}", user_addr),
+        )
+    }
+
+    /// carries bare `ProxyAccChanged` messages for subscribers predating versions
     pub fn get_proxy_acc_chan() -> String {
         "proxy_acc_updated".to_string()
     }
 
+    /// carries the same changes as `VersionedProxyAccChanged`
+    pub fn get_proxy_acc_versioned_chan() -> String {
+        "proxy_acc_versioned".to_string()
+    }
+
+    /// in the slot of the price hash, see `get_peers_chan`
     pub fn get_price_chan() -> String {
-        "price_updated".to_string()
+        Self::in_slot_of(Self::get_price_kf("".to_owned()).0, "_updated")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::cluster_routing::get_slot;
+
+    use super::*;
+
+    #[test]
+    fn test_namespace() {
+        let k = DPNRedisKey::get_peers_k("ms1".to_owned());
+        assert_eq!(DPNRedisKey::with_namespace("", k.clone()), "peers_ms#ms1");
+        assert_eq!(
+            DPNRedisKey::with_namespace("staging", k),
+            "staging:peers_ms#ms1"
+        );
+        let k = DPNRedisKey::get_peer_queue_k("ms1".to_owned());
+        assert_eq!(
+            DPNRedisKey::with_namespace("staging", k),
+            "{staging:peers_ms#ms1}_queue"
+        );
+
+        let families = DPNRedisKey::families();
+        let mut names: Vec<&str> = families.iter().map(|f| f.name).collect();
+        names.dedup();
+        assert_eq!(names.len(), families.len());
+        assert!(families
+            .iter()
+            .any(|f| f.pattern == "peers_ms#*" && f.kind == RedisKeyKind::Hash));
+    }
+
+    #[test]
+    fn test_conn_builder_from_uri() {
+        assert!(RedisService::get_redis_conn_builder_from_uri("redis://:secret@r1:6379").is_ok());
+        assert!(RedisService::get_redis_conn_builder_from_uri(
+            "redis+sentinel://s1:26379/mymaster"
+        )
+        .is_err());
+        assert!(
+            RedisService::get_redis_conn_builder_from_uri("redis+cluster://n1:7000,n2:7001")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_key_slots() {
+        let same_slot = |keys: Vec<String>| {
+            for namespace in ["", "staging"] {
+                let slots: Vec<u16> = keys
+                    .iter()
+                    .map(|k| DPNRedisKey::with_namespace(namespace, k.clone()))
+                    .map(|k| get_slot(k.as_bytes()))
+                    .collect();
+                assert!(slots.windows(2).all(|w| w[0] == w[1]), "{:?}", keys);
+            }
+        };
+
+        let ms = || "ms1".to_owned();
+        // lease_peer, release_peer, migrate_legacy_peer_ips and the reap script
+        same_slot(vec![
+            DPNRedisKey::get_peers_k(ms()),
+            DPNRedisKey::get_peer_queue_k(ms()),
+            DPNRedisKey::get_masternode_lease_k(ms()),
+            DPNRedisKey::get_write_fence_k(DPNRedisKey::get_peers_k(ms())),
+        ]);
+        // publish_peer and remove_all_peers, the message goes in the transaction
+        same_slot(vec![
+            DPNRedisKey::get_peers_k(ms()),
+            DPNRedisKey::get_peers_chan(ms()),
+        ]);
+        // publish_peer_price
+        same_slot(vec![
+            DPNRedisKey::get_price_kf("0x1".to_owned()).0,
+            DPNRedisKey::get_price_chan(),
+        ]);
+        // publish_proxy_acc and get_proxy_accs_snapshot
+        same_slot(vec![
+            DPNRedisKey::get_proxy_acc_kf("".to_owned()).0,
+            DPNRedisKey::get_proxy_acc_version_k(),
+            DPNRedisKey::get_write_fence_k(DPNRedisKey::get_proxy_acc_kf("".to_owned()).0),
+        ]);
+        // uptime accumulation
+        same_slot(vec![
+            DPNRedisKey::get_uptime_xp_kf("".to_owned()).0,
+            DPNRedisKey::get_uptime_xp_created_kf("".to_owned()).0,
+            DPNRedisKey::get_uptime_xp_pending_kf("".to_owned()).0,
+            DPNRedisKey::get_uptime_xp_credited_k("0x1".to_owned()),
+        ]);
+        // lock acquire and fence bump
+        same_slot(vec![
+            DPNRedisKey::get_lock_k("reaper".to_owned()),
+            DPNRedisKey::get_lock_fence_k("reaper".to_owned()),
+        ]);
+
+        assert_ne!(
+            get_slot(DPNRedisKey::get_peers_k("ms1".to_owned()).as_bytes()),
+            get_slot(DPNRedisKey::get_peers_k("ms2".to_owned()).as_bytes())
+        );
     }
 }
@@ -0,0 +1,185 @@
+use std::fmt::Debug;
+
+use redis::Pipeline;
+use serde::Serialize;
+
+use super::redis::DPNRedisKey;
+
+/// commands sent to redis in one round trip, see `RedisService::batch`
+/// and `RedisService::transaction`
+///
+/// keys and channels are the ones built by `DPNRedisKey`, the namespace of the
+/// service is applied here. writes give no reply, reads like `get` and `hgetall`
+/// reply in order and are decoded by `RedisService::query`
+#[derive(Clone)]
+pub struct RedisBatch {
+    namespace: String,
+    pipe: Pipeline,
+    len: usize,
+    atomic: bool,
+}
+
+impl Debug for RedisBatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisBatch")
+            .field("namespace", &self.namespace)
+            .field("len", &self.len)
+            .field("atomic", &self.atomic)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RedisBatch {
+    pub(crate) fn new(namespace: String, atomic: bool) -> Self {
+        let mut pipe = redis::pipe();
+        if atomic {
+            pipe.atomic();
+        }
+        Self {
+            namespace,
+            pipe,
+            len: 0,
+            atomic,
+        }
+    }
+
+    fn namespaced(&self, key: String) -> String {
+        DPNRedisKey::with_namespace(&self.namespace, key)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// whether the batch was started by `RedisService::transaction`
+    pub fn is_atomic(&self) -> bool {
+        self.atomic
+    }
+
+    pub fn hset<T: Serialize>(&mut self, key: String, field: String, obj: &T) -> &mut Self {
+        self.hset_many(key, vec![(field, obj)])
+    }
+
+    pub fn hset_many<T: Serialize>(&mut self, key: String, items: Vec<(String, T)>) -> &mut Self {
+        if items.is_empty() {
+            return self;
+        }
+        let items: Vec<(String, String)> = items
+            .into_iter()
+            .map(|(field, obj)| (field, serde_json::to_string(&obj).unwrap()))
+            .collect();
+        let key = self.namespaced(key);
+        self.pipe.hset_multiple(key, &items).ignore();
+        self.len += 1;
+        self
+    }
+
+    pub fn hdel(&mut self, key: String, field: String) -> &mut Self {
+        let key = self.namespaced(key);
+        self.pipe.hdel(key, field).ignore();
+        self.len += 1;
+        self
+    }
+
+    pub fn del(&mut self, key: String) -> &mut Self {
+        let key = self.namespaced(key);
+        self.pipe.del(key).ignore();
+        self.len += 1;
+        self
+    }
+
+    pub fn incr(&mut self, key: String) -> &mut Self {
+        let key = self.namespaced(key);
+        self.pipe.incr(key, 1).ignore();
+        self.len += 1;
+        self
+    }
+
+    pub fn zadd(&mut self, key: String, score: u32, value: String) -> &mut Self {
+        let key = self.namespaced(key);
+        self.pipe.zadd(key, value, score).ignore();
+        self.len += 1;
+        self
+    }
+
+    /// sets the score of members already in the sorted set, members that left it are not added back
+    pub fn zupdate_many(&mut self, key: String, items: Vec<(u32, String)>) -> &mut Self {
+        if items.is_empty() {
+            return self;
+        }
+        let key = self.namespaced(key);
+        let cmd = self.pipe.cmd("ZADD").arg(key).arg("XX");
+        for (score, value) in items {
+            cmd.arg(score).arg(value);
+        }
+        cmd.ignore();
+        self.len += 1;
+        self
+    }
+
+    pub fn zrem(&mut self, key: String, value: String) -> &mut Self {
+        let key = self.namespaced(key);
+        self.pipe.zrem(key, value).ignore();
+        self.len += 1;
+        self
+    }
+
+    pub fn publish<T: Serialize>(&mut self, chan_name: String, msg: &T) -> &mut Self {
+        let chan_name = self.namespaced(chan_name);
+        self.pipe
+            .publish(chan_name, serde_json::to_string(msg).unwrap())
+            .ignore();
+        self.len += 1;
+        self
+    }
+
+    /// replies with the value of the key, or nil
+    pub fn get(&mut self, key: String) -> &mut Self {
+        let key = self.namespaced(key);
+        self.pipe.get(key);
+        self.len += 1;
+        self
+    }
+
+    /// replies with the fields and raw values of the hash
+    pub fn hgetall(&mut self, key: String) -> &mut Self {
+        let key = self.namespaced(key);
+        self.pipe.hgetall(key);
+        self.len += 1;
+        self
+    }
+
+    pub(crate) fn pipe(&self) -> &Pipeline {
+        &self.pipe
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_is_namespaced() {
+        let mut batch = RedisBatch::new("staging".to_owned(), true);
+        assert!(batch.is_empty() && batch.is_atomic());
+        batch
+            .hset(
+                DPNRedisKey::get_peers_k("a".to_owned()),
+                "1".to_owned(),
+                &"info",
+            )
+            .publish(DPNRedisKey::get_peers_chan("a".to_owned()), &"change");
+
+        let packed = String::from_utf8(batch.pipe().get_packed_pipeline()).unwrap();
+        assert!(packed.contains("MULTI"));
+        assert!(packed.contains("staging:peers_ms#a"));
+        assert!(packed.contains("{staging:peers_ms#a}_updated"));
+        assert!(packed.contains("\"info\""));
+
+        let batch = RedisBatch::new("staging".to_owned(), false);
+        assert!(!batch.is_atomic());
+        assert!(!String::from_utf8(batch.pipe().get_packed_pipeline())
+            .unwrap()
+            .contains("MULTI"));
+    }
+}
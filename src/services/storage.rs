@@ -0,0 +1,583 @@
+use std::{fmt::Debug, sync::Arc};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use mockall::automock;
+use serde_json::Value;
+
+use crate::types::{
+    accounting::UserBalance,
+    bandwidth::UserBandwidthPrice,
+    connection::{PeerIp, ProxyAccData},
+};
+
+use super::{
+    subscription::Subscription,
+    types::{
+        DebitOutcome, PeerChanged, PeerChangedInfo, ProxyAccChanged, VersionedProxyAccChanged,
+    },
+};
+
+/// storage used by masternodes and admin to share peers, prices and proxy accs
+///
+/// values are json documents, `RedisService` is the production backend and
+/// `InMemoryStorageService` an in-process one with the same behaviour for tests
+#[automock]
+#[async_trait]
+pub trait StorageService: Debug + Send + Sync + 'static {
+    // hash
+    async fn hset(self: Arc<Self>, key: String, field: String, value: Value) -> Result<()>;
+    /// fails if the field does not exist
+    async fn hget(self: Arc<Self>, key: String, field: String) -> Result<Value>;
+    async fn hgetall(self: Arc<Self>, key: String) -> Result<Vec<(String, Value)>>;
+    async fn hdel(self: Arc<Self>, key: String, field: String) -> Result<()>;
+
+    // sorted set
+    async fn zadd(self: Arc<Self>, key: String, score: u32, value: String) -> Result<()>;
+    async fn zrem(self: Arc<Self>, key: String, value: String) -> Result<()>;
+    async fn zsetall(self: Arc<Self>, key: String, score: u32) -> Result<()>;
+    /// returns (value, score) ordered by score
+    async fn zgetall(self: Arc<Self>, key: String) -> Result<Vec<(String, u32)>>;
+
+    // key
+    async fn del(self: Arc<Self>, key: String) -> Result<()>;
+
+    // pub/sub
+    async fn publish(self: Arc<Self>, chan_name: String, obj_str: String) -> Result<()>;
+    async fn subscribe(self: Arc<Self>, chan_name: String) -> Result<Subscription<Value>>;
+
+    // peers
+    async fn publish_peer(
+        self: Arc<Self>,
+        masternode_id: String,
+        status: PeerChanged,
+    ) -> Result<()>;
+    async fn get_peers(self: Arc<Self>, masternode_id: String) -> Result<Vec<PeerChangedInfo>>;
+    async fn remove_all_peers(self: Arc<Self>, masternode_id: String) -> Result<()>;
+    async fn subscribe_peers(
+        self: Arc<Self>,
+        masternode_id: String,
+    ) -> Result<Subscription<PeerChanged>>;
+
+    // peer leases
+    /// leases the least used peer of the masternode peer queue,
+    /// see `RedisService::lease_peer`
+    async fn lease_peer(
+        self: Arc<Self>,
+        masternode_id: String,
+    ) -> Result<Option<(PeerIp, PeerChangedInfo)>>;
+    async fn release_peer(self: Arc<Self>, masternode_id: String, ip: PeerIp) -> Result<()>;
+
+    // balances
+    /// a client without balance has zero
+    async fn get_balance(self: Arc<Self>, user_addr: String) -> Result<UserBalance>;
+    async fn credit(self: Arc<Self>, user_addr: String, amount: i64) -> Result<UserBalance>;
+    /// leaves the balance untouched when it does not cover the amount
+    async fn debit_if_sufficient(
+        self: Arc<Self>,
+        user_addr: String,
+        amount: i64,
+    ) -> Result<DebitOutcome>;
+
+    // prices
+    async fn publish_peer_price(self: Arc<Self>, price: UserBandwidthPrice) -> Result<()>;
+    async fn get_peers_price(self: Arc<Self>) -> Result<Vec<UserBandwidthPrice>>;
+
+    // proxy accs
+    async fn publish_proxy_acc(self: Arc<Self>, proxy_acc_changed: ProxyAccChanged) -> Result<()>;
+    async fn get_proxy_accs(self: Arc<Self>) -> Result<Vec<ProxyAccData>>;
+    async fn get_proxy_accs_snapshot(self: Arc<Self>) -> Result<(u64, Vec<ProxyAccData>)>;
+    async fn get_proxy_acc_version(self: Arc<Self>) -> Result<u64>;
+    async fn remove_all_proxy_accs(self: Arc<Self>) -> Result<()>;
+    async fn subscribe_proxy_accs(
+        self: Arc<Self>,
+    ) -> Result<Subscription<VersionedProxyAccChanged>>;
+}
+
+/// behaviour every `StorageService` backend must share
+#[cfg(test)]
+pub(crate) mod conformance {
+    use std::time::Duration;
+
+    use futures::StreamExt as _;
+    use serde_json::json;
+
+    use super::*;
+    use crate::services::{error::RedisServiceError, redis::DPNRedisKey};
+
+    async fn next<T>(sub: &mut Subscription<T>) -> T {
+        tokio::time::timeout(Duration::from_secs(5), sub.next())
+            .await
+            .expect("no message received")
+            .expect("subscription ended")
+            .expect("message failed to decode")
+    }
+
+    fn info(ip: &str) -> PeerChangedInfo {
+        PeerChangedInfo {
+            uuid: format!("uuid_{}", ip),
+            login_session_id: format!("session_{}", ip),
+            ip: ip.parse().unwrap(),
+        }
+    }
+
+    /// runs the whole suite, `ns` keeps keys of concurrent runs apart
+    pub async fn run(storage: Arc<dyn StorageService>, ns: &str) {
+        hash(storage.clone(), ns).await;
+        sorted_set(storage.clone(), ns).await;
+        pubsub(storage.clone(), ns).await;
+        peers(storage.clone(), ns).await;
+        peer_leases(storage.clone(), ns).await;
+        balances(storage.clone(), ns).await;
+        proxy_accs(storage.clone()).await;
+    }
+
+    async fn hash(storage: Arc<dyn StorageService>, ns: &str) {
+        let k = format!("{}_hash", ns);
+        storage.clone().del(k.clone()).await.unwrap();
+
+        storage
+            .clone()
+            .hset(k.clone(), "a".to_owned(), json!({"v": 1}))
+            .await
+            .unwrap();
+        storage
+            .clone()
+            .hset(k.clone(), "b".to_owned(), json!("two"))
+            .await
+            .unwrap();
+        let a = storage.clone().hget(k.clone(), "a".to_owned()).await;
+        assert_eq!(a.unwrap(), json!({"v": 1}));
+        assert!(storage
+            .clone()
+            .hget(k.clone(), "c".to_owned())
+            .await
+            .is_err());
+
+        let mut all = storage.clone().hgetall(k.clone()).await.unwrap();
+        all.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            all,
+            vec![
+                ("a".to_owned(), json!({"v": 1})),
+                ("b".to_owned(), json!("two"))
+            ]
+        );
+
+        storage
+            .clone()
+            .hdel(k.clone(), "a".to_owned())
+            .await
+            .unwrap();
+        storage
+            .clone()
+            .hdel(k.clone(), "a".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(storage.clone().hgetall(k.clone()).await.unwrap().len(), 1);
+
+        storage.clone().del(k.clone()).await.unwrap();
+        storage.clone().del(k.clone()).await.unwrap();
+        assert!(storage.clone().hgetall(k).await.unwrap().is_empty());
+    }
+
+    async fn sorted_set(storage: Arc<dyn StorageService>, ns: &str) {
+        let k = format!("{}_zset", ns);
+        storage.clone().del(k.clone()).await.unwrap();
+
+        let z = |value: &str, score: u32| (value.to_owned(), score);
+        storage
+            .clone()
+            .zadd(k.clone(), 2, "7".to_owned())
+            .await
+            .unwrap();
+        storage
+            .clone()
+            .zadd(k.clone(), 1, "9".to_owned())
+            .await
+            .unwrap();
+        storage
+            .clone()
+            .zadd(k.clone(), 1, "10".to_owned())
+            .await
+            .unwrap();
+        // members with equal scores are ordered by their string form
+        let all = storage.clone().zgetall(k.clone()).await.unwrap();
+        assert_eq!(all, vec![z("10", 1), z("9", 1), z("7", 2)]);
+
+        storage
+            .clone()
+            .zadd(k.clone(), 0, "7".to_owned())
+            .await
+            .unwrap();
+        storage
+            .clone()
+            .zrem(k.clone(), "9".to_owned())
+            .await
+            .unwrap();
+        storage
+            .clone()
+            .zrem(k.clone(), "9".to_owned())
+            .await
+            .unwrap();
+        let all = storage.clone().zgetall(k.clone()).await.unwrap();
+        assert_eq!(all, vec![z("7", 0), z("10", 1)]);
+
+        storage.clone().zsetall(k.clone(), 5).await.unwrap();
+        let all = storage.clone().zgetall(k.clone()).await.unwrap();
+        assert_eq!(all, vec![z("10", 5), z("7", 5)]);
+
+        storage.clone().del(k.clone()).await.unwrap();
+        assert!(storage.clone().zgetall(k).await.unwrap().is_empty());
+    }
+
+    async fn pubsub(storage: Arc<dyn StorageService>, ns: &str) {
+        let chan = format!("{}_chan", ns);
+        let mut sub = storage.clone().subscribe(chan.clone()).await.unwrap();
+
+        storage
+            .clone()
+            .publish(chan.clone(), r#"{"v":1}"#.to_owned())
+            .await
+            .unwrap();
+        assert_eq!(next(&mut sub).await, json!({"v": 1}));
+
+        storage
+            .clone()
+            .publish(chan.clone(), "not json".to_owned())
+            .await
+            .unwrap();
+        let err = tokio::time::timeout(Duration::from_secs(5), sub.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.chan, chan);
+        assert_eq!(err.payload, "not json");
+
+        // subscribers of one channel neither steal nor cancel each other's messages
+        let mut other = storage.clone().subscribe(chan.clone()).await.unwrap();
+        storage
+            .clone()
+            .publish(chan.clone(), r#"{"v":2}"#.to_owned())
+            .await
+            .unwrap();
+        assert_eq!(next(&mut sub).await, json!({"v": 2}));
+        assert_eq!(next(&mut other).await, json!({"v": 2}));
+
+        drop(other);
+        storage
+            .clone()
+            .publish(chan.clone(), r#"{"v":3}"#.to_owned())
+            .await
+            .unwrap();
+        assert_eq!(next(&mut sub).await, json!({"v": 3}));
+    }
+
+    async fn peers(storage: Arc<dyn StorageService>, ns: &str) {
+        let masternode_id = format!("{}_masternode", ns);
+        storage
+            .clone()
+            .remove_all_peers(masternode_id.clone())
+            .await
+            .unwrap();
+        let mut sub = storage
+            .clone()
+            .subscribe_peers(masternode_id.clone())
+            .await
+            .unwrap();
+
+        for ip in ["10.0.0.1", "2001:db8::1"] {
+            storage
+                .clone()
+                .publish_peer(masternode_id.clone(), PeerChanged::Connected(info(ip)))
+                .await
+                .unwrap();
+            let msg = next(&mut sub).await;
+            assert!(matches!(msg, PeerChanged::Connected(i) if i.ip.to_string() == ip));
+        }
+        let mut peers = storage
+            .clone()
+            .get_peers(masternode_id.clone())
+            .await
+            .unwrap();
+        peers.sort_by_key(|p| p.ip);
+        assert_eq!(
+            peers.iter().map(|p| p.ip.to_string()).collect::<Vec<_>>(),
+            vec!["10.0.0.1", "2001:db8::1"]
+        );
+
+        storage
+            .clone()
+            .publish_peer(
+                masternode_id.clone(),
+                PeerChanged::Disconnected(info("10.0.0.1")),
+            )
+            .await
+            .unwrap();
+        let msg = next(&mut sub).await;
+        assert!(matches!(msg, PeerChanged::Disconnected(i) if i.ip.to_string() == "10.0.0.1"));
+        let peers = storage
+            .clone()
+            .get_peers(masternode_id.clone())
+            .await
+            .unwrap();
+        assert_eq!(peers.len(), 1);
+
+        storage
+            .clone()
+            .remove_all_peers(masternode_id.clone())
+            .await
+            .unwrap();
+        let msg = next(&mut sub).await;
+        assert!(matches!(msg, PeerChanged::Disconnected(i) if i.ip.to_string() == "2001:db8::1"));
+        let peers = storage.clone().get_peers(masternode_id).await.unwrap();
+        assert!(peers.is_empty());
+    }
+
+    async fn peer_leases(storage: Arc<dyn StorageService>, ns: &str) {
+        let masternode_id = format!("{}_lease_masternode", ns);
+        let queue_k = DPNRedisKey::get_peer_queue_k(masternode_id.clone());
+        storage
+            .clone()
+            .remove_all_peers(masternode_id.clone())
+            .await
+            .unwrap();
+        storage.clone().del(queue_k.clone()).await.unwrap();
+        let lease = || async {
+            storage
+                .clone()
+                .lease_peer(masternode_id.clone())
+                .await
+                .unwrap()
+                .map(|(ip, _)| ip.to_string())
+        };
+        assert_eq!(lease().await, None);
+
+        for ip in ["10.0.0.1", "10.0.0.2"] {
+            storage
+                .clone()
+                .publish_peer(masternode_id.clone(), PeerChanged::Connected(info(ip)))
+                .await
+                .unwrap();
+        }
+        // 10.0.0.9 has left the peers hash but not the queue
+        for ip in ["10.0.0.1", "10.0.0.2", "10.0.0.9"] {
+            storage
+                .clone()
+                .zadd(queue_k.clone(), 0, ip.to_owned())
+                .await
+                .unwrap();
+        }
+
+        // least used first, ties by ip, the stale entry is dropped on the way
+        assert_eq!(lease().await.as_deref(), Some("10.0.0.1"));
+        assert_eq!(lease().await.as_deref(), Some("10.0.0.2"));
+        assert_eq!(lease().await.as_deref(), Some("10.0.0.1"));
+        let scores = || async { storage.clone().zgetall(queue_k.clone()).await.unwrap() };
+        assert_eq!(
+            scores().await,
+            vec![("10.0.0.2".to_owned(), 1), ("10.0.0.1".to_owned(), 2)]
+        );
+
+        // a release scores the peer down again, never below zero
+        let release = |ip: &str| {
+            storage
+                .clone()
+                .release_peer(masternode_id.clone(), ip.parse().unwrap())
+        };
+        release("10.0.0.1").await.unwrap();
+        release("10.0.0.2").await.unwrap();
+        release("10.0.0.2").await.unwrap();
+        assert_eq!(
+            scores().await,
+            vec![("10.0.0.2".to_owned(), 0), ("10.0.0.1".to_owned(), 1)]
+        );
+        // an ip no longer queued is not added back
+        release("10.0.0.9").await.unwrap();
+        assert_eq!(scores().await.len(), 2);
+        assert_eq!(lease().await.as_deref(), Some("10.0.0.2"));
+
+        storage
+            .clone()
+            .remove_all_peers(masternode_id.clone())
+            .await
+            .unwrap();
+        storage.clone().del(queue_k).await.unwrap();
+    }
+
+    async fn balances(storage: Arc<dyn StorageService>, ns: &str) {
+        let user_addr = format!("{}_user", ns);
+        let (k, f) = DPNRedisKey::get_balance_kf(user_addr.clone());
+        storage.clone().hdel(k.clone(), f.clone()).await.unwrap();
+        let balance = || async {
+            storage
+                .clone()
+                .get_balance(user_addr.clone())
+                .await
+                .unwrap()
+                .balance
+        };
+        let debit = |amount: i64| {
+            storage
+                .clone()
+                .debit_if_sufficient(user_addr.clone(), amount)
+        };
+        let is_invalid = |e: anyhow::Error| {
+            matches!(
+                e.downcast_ref::<RedisServiceError>(),
+                Some(RedisServiceError::InvalidArgument(_))
+            )
+        };
+        assert_eq!(balance().await, 0);
+        assert!(is_invalid(
+            storage
+                .clone()
+                .credit(user_addr.clone(), -1)
+                .await
+                .unwrap_err()
+        ));
+        assert!(is_invalid(debit(-1).await.unwrap_err()));
+
+        let credited = storage
+            .clone()
+            .credit(user_addr.clone(), 100)
+            .await
+            .unwrap();
+        assert_eq!(credited.balance, 100);
+
+        // insufficient, the balance is untouched
+        assert!(matches!(
+            debit(101).await.unwrap(),
+            DebitOutcome::Insufficient(b) if b.balance == 100
+        ));
+        assert!(matches!(
+            debit(40).await.unwrap(),
+            DebitOutcome::Debited(b) if b.balance == 60
+        ));
+        // the exact balance can be debited down to zero
+        assert!(matches!(
+            debit(60).await.unwrap(),
+            DebitOutcome::Debited(b) if b.balance == 0
+        ));
+        assert!(matches!(
+            debit(1).await.unwrap(),
+            DebitOutcome::Insufficient(b) if b.balance == 0
+        ));
+
+        // concurrent debits never overdraw, only as many as the balance covers pass
+        storage
+            .clone()
+            .credit(user_addr.clone(), 100)
+            .await
+            .unwrap();
+        let outcomes = futures::future::join_all((0..10).map(|_| {
+            let storage = storage.clone();
+            let user_addr = user_addr.clone();
+            tokio::spawn(async move { storage.debit_if_sufficient(user_addr, 30).await })
+        }))
+        .await;
+        let debited = outcomes
+            .into_iter()
+            .map(|o| o.unwrap().unwrap())
+            .filter(|o| matches!(o, DebitOutcome::Debited(_)))
+            .count();
+        assert_eq!(debited, 3);
+        assert_eq!(balance().await, 10);
+
+        // an overflowing credit is refused and leaves the balance untouched
+        let overflow = storage
+            .clone()
+            .credit(user_addr.clone(), i64::MAX)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            overflow.downcast_ref::<RedisServiceError>(),
+            Some(RedisServiceError::Command { .. })
+        ));
+        assert_eq!(balance().await, 10);
+
+        storage.hdel(k, f).await.unwrap();
+    }
+
+    async fn proxy_accs(storage: Arc<dyn StorageService>) {
+        let pad = |id, rate_per_kb| ProxyAccData {
+            rate_per_kb,
+            ..ProxyAccData::fixture(id)
+        };
+        storage.clone().remove_all_proxy_accs().await.unwrap();
+        let mut sub = storage.clone().subscribe_proxy_accs().await.unwrap();
+        // subscribers predating versions keep receiving the bare change
+        let mut legacy_sub = storage
+            .clone()
+            .subscribe(DPNRedisKey::get_proxy_acc_chan())
+            .await
+            .unwrap();
+        let (version, proxy_accs) = storage.clone().get_proxy_accs_snapshot().await.unwrap();
+        assert!(proxy_accs.is_empty());
+
+        let changes = vec![
+            ProxyAccChanged::Created(pad("a", 1)),
+            ProxyAccChanged::Created(pad("b", 1)),
+            ProxyAccChanged::Updated(pad("a", 2)),
+            ProxyAccChanged::Deleted("b".to_owned()),
+        ];
+        for (i, change) in changes.into_iter().enumerate() {
+            storage
+                .clone()
+                .publish_proxy_acc(change.clone())
+                .await
+                .unwrap();
+            let msg = next(&mut sub).await;
+            assert_eq!(msg.version, version + i as u64 + 1);
+            assert_eq!(
+                next(&mut legacy_sub).await,
+                serde_json::to_value(&change).unwrap()
+            );
+        }
+
+        let (snapshot_version, proxy_accs) =
+            storage.clone().get_proxy_accs_snapshot().await.unwrap();
+        assert_eq!(snapshot_version, version + 4);
+        assert_eq!(proxy_accs.len(), 1);
+        assert_eq!(proxy_accs[0].rate_per_kb, 2);
+        assert_eq!(storage.clone().get_proxy_accs().await.unwrap().len(), 1);
+        assert_eq!(
+            storage.clone().get_proxy_acc_version().await.unwrap(),
+            version + 4
+        );
+
+        storage.clone().remove_all_proxy_accs().await.unwrap();
+        assert!(storage.clone().get_proxy_accs().await.unwrap().is_empty());
+        assert_eq!(
+            storage.clone().get_proxy_acc_version().await.unwrap(),
+            version + 5
+        );
+        let msg = next(&mut sub).await;
+        assert_eq!(msg.version, version + 5);
+        assert!(matches!(msg.change, ProxyAccChanged::RefreshAll()));
+        let (k, _) = DPNRedisKey::get_proxy_acc_kf("".to_owned());
+        assert!(storage.hgetall(k).await.unwrap().is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::{memory::InMemoryStorageService, redis::RedisService};
+
+    #[tokio::test]
+    async fn test_memory_conformance() {
+        conformance::run(Arc::new(InMemoryStorageService::new()), "test").await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a disposable redis server in DPN_TEST_REDIS_URI"]
+    async fn test_redis_conformance() {
+        let redis_uri = std::env::var("DPN_TEST_REDIS_URI").unwrap();
+        let redis_service = RedisService::new_with_namespace(redis_uri, "dpn_test".to_owned())
+            .await
+            .unwrap();
+        conformance::run(Arc::new(redis_service), "test").await;
+    }
+}
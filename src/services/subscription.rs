@@ -0,0 +1,411 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    pin::pin,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use futures::{
+    future::{self, Either},
+    stream,
+    stream::BoxStream,
+    StreamExt as _,
+};
+use log::warn;
+use redis_async::{
+    client::PubsubConnection,
+    error::Error as PubsubError,
+    resp::{FromResp, RespValue},
+};
+use serde::de::DeserializeOwned;
+use tokio::{
+    runtime::Handle,
+    sync::{
+        broadcast::{self, error::RecvError},
+        watch,
+    },
+};
+
+/// delay between two resubscribe attempts while the pubsub connection is down
+pub const RESUBSCRIBE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// messages a slow subscriber can lag behind the others before it starts losing them
+pub const FEED_CAPACITY: usize = 1024;
+
+use super::redis_conn::RedisConnConfig;
+
+type PubsubSource = BoxStream<'static, Result<RespValue, PubsubError>>;
+
+/// typed stream of messages published on a single channel,
+/// messages that cannot be decoded are yielded as `Err` instead of being dropped
+pub type Subscription<T> = BoxStream<'static, Result<T, SubscriptionDecodeError>>;
+
+/// a message received on a channel that does not decode into the expected type
+#[derive(Debug, Clone)]
+pub struct SubscriptionDecodeError {
+    pub chan: String,
+    pub payload: String,
+    pub err: String,
+}
+
+impl fmt::Display for SubscriptionDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed to decode message chan={} payload={} err={}",
+            self.chan, self.payload, self.err
+        )
+    }
+}
+
+impl std::error::Error for SubscriptionDecodeError {}
+
+struct PubsubTarget {
+    /// bumped on every reconnect so feeds failing together reconnect once
+    generation: u64,
+    pubsub_con: PubsubConnection,
+}
+
+/// one redis subscription per channel, shared by every `Subscription` of the channel
+///
+/// redis_async keeps a single sink per channel on a connection and dropping a
+/// `PubsubStream` unsubscribes the whole channel, so two streams of one channel would
+/// keep replacing and unsubscribing each other. the hub holds the only stream of each
+/// channel in a task and fans its messages out, a channel stays subscribed until its
+/// last `Subscription` or the hub is dropped
+///
+/// a hub made by `connect` opens a new connection when a subscription is lost, to the
+/// node `RedisConnConfig::resolve_pubsub_node` gives at that time
+pub struct PubsubHub {
+    config: Option<RedisConnConfig>,
+    current: Mutex<PubsubTarget>,
+    reconnecting: tokio::sync::Mutex<()>,
+    feeds: tokio::sync::Mutex<HashMap<String, Feed>>,
+}
+
+struct Feed {
+    tx: broadcast::Sender<RespValue>,
+    /// dropped with the feed, which stops its task
+    _stop: watch::Sender<()>,
+}
+
+/// releases the channel from the hub once the last `Subscription` of it is dropped
+struct FeedLease {
+    hub: Weak<PubsubHub>,
+    chan: String,
+}
+
+impl Drop for FeedLease {
+    fn drop(&mut self) {
+        let (Some(hub), Ok(runtime)) = (self.hub.upgrade(), Handle::try_current()) else {
+            return;
+        };
+        // the receiver of the subscription is gone by the time the task runs
+        runtime.spawn(hub.release(std::mem::take(&mut self.chan)));
+    }
+}
+
+impl fmt::Debug for PubsubHub {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PubsubHub")
+            .field("config", &self.config)
+            .field("generation", &self.current.lock().unwrap().generation)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PubsubHub {
+    /// hub over a fixed connection, lost subscriptions are made again on it
+    pub fn new(pubsub_con: PubsubConnection) -> Self {
+        Self::with_target(None, pubsub_con)
+    }
+
+    /// hub following the pubsub node of `config` across failovers
+    pub async fn connect(config: RedisConnConfig) -> Result<Self> {
+        let pubsub_con = config.pubsub_connect(0).await?;
+        Ok(Self::with_target(Some(config), pubsub_con))
+    }
+
+    fn with_target(config: Option<RedisConnConfig>, pubsub_con: PubsubConnection) -> Self {
+        Self {
+            config,
+            current: Mutex::new(PubsubTarget {
+                generation: 0,
+                pubsub_con,
+            }),
+            reconnecting: tokio::sync::Mutex::new(()),
+            feeds: tokio::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn pubsub_conn(&self) -> PubsubConnection {
+        self.current.lock().unwrap().pubsub_con.clone()
+    }
+
+    /// subscribes to `chan` and decodes every message as json into `T`
+    ///
+    /// the first subscription of a channel is made before returning so no message
+    /// published afterwards is missed, later on the channel resubscribes by itself
+    /// whenever the underlying connection drops. messages published while disconnected
+    /// are lost, so are messages a subscriber lags `FEED_CAPACITY` behind on
+    pub async fn subscribe_json<T>(self: Arc<Self>, chan: String) -> Result<Subscription<T>>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let lease = FeedLease {
+            hub: Arc::downgrade(&self),
+            chan: chan.clone(),
+        };
+        let mut feeds = self.feeds.lock().await;
+        if let Some(feed) = feeds.get(&chan) {
+            return Ok(receiver_stream(chan, feed.tx.subscribe(), lease));
+        }
+
+        let (generation, source) = self.clone().subscribe_source(&chan).await?;
+        let (tx, rx) = broadcast::channel(FEED_CAPACITY);
+        let (stop, stop_rx) = watch::channel(());
+        tokio::spawn(feed(
+            Arc::downgrade(&self),
+            chan.clone(),
+            generation,
+            source,
+            tx.clone(),
+            stop_rx,
+        ));
+        feeds.insert(chan.clone(), Feed { tx, _stop: stop });
+        Ok(receiver_stream(chan, rx, lease))
+    }
+
+    /// drops the feed of `chan` if no subscriber is left, its task then stops and
+    /// unsubscribes the channel
+    async fn release(self: Arc<Self>, chan: String) {
+        let mut feeds = self.feeds.lock().await;
+        if feeds
+            .get(&chan)
+            .is_some_and(|feed| feed.tx.receiver_count() == 0)
+        {
+            feeds.remove(&chan);
+        }
+    }
+
+    /// subscribes on the current connection, returns the generation of the connection used
+    async fn subscribe_source(self: Arc<Self>, chan: &str) -> Result<(u64, PubsubSource)> {
+        let (generation, pubsub_con) = {
+            let current = self.current.lock().unwrap();
+            (current.generation, current.pubsub_con.clone())
+        };
+        let inner = pubsub_con
+            .subscribe(chan)
+            .await
+            .map_err(|e| anyhow!("redis subscribe failed chan={} err={}", chan, e))?;
+        Ok((generation, inner.boxed()))
+    }
+
+    /// replaces the connection of `failed_generation` unless another feed did already,
+    /// returns the generation of the connection to use from now on
+    async fn reconnect(self: Arc<Self>, failed_generation: u64) -> Result<u64> {
+        let _reconnecting = self.reconnecting.lock().await;
+        let generation = self.current.lock().unwrap().generation;
+        let Some(config) = &self.config else {
+            return Ok(generation);
+        };
+        if generation != failed_generation {
+            return Ok(generation);
+        }
+        let generation = generation + 1;
+        let pubsub_con = config.pubsub_connect(generation).await?;
+        *self.current.lock().unwrap() = PubsubTarget {
+            generation,
+            pubsub_con,
+        };
+        Ok(generation)
+    }
+}
+
+/// forwards the messages of a channel to its subscribers until its `Feed` is dropped
+async fn feed(
+    hub: Weak<PubsubHub>,
+    chan: String,
+    mut generation: u64,
+    mut source: PubsubSource,
+    tx: broadcast::Sender<RespValue>,
+    mut stop: watch::Receiver<()>,
+) {
+    loop {
+        match until_stopped(&mut stop, source.next()).await {
+            None => return,
+            Some(Some(Ok(resp))) => {
+                // the last subscriber may be gone, the hub releases the feed then
+                _ = tx.send(resp);
+                continue;
+            }
+            Some(Some(Err(e))) => warn!("redis subscription lost chan={} err={}", chan, e),
+            Some(None) => warn!("redis subscription ended chan={}", chan),
+        }
+
+        // the old stream unsubscribes the channel when dropped, drop it before resubscribing
+        drop(source);
+        source = loop {
+            let Some(hub) = hub.upgrade() else {
+                return;
+            };
+            let result = match hub.clone().reconnect(generation).await {
+                Ok(current) => {
+                    generation = current;
+                    hub.subscribe_source(&chan).await
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok((current, new_source)) => {
+                    generation = current;
+                    break new_source;
+                }
+                Err(e) => warn!("redis resubscribe failed chan={} err={}", chan, e),
+            }
+            let wait = tokio::time::sleep(RESUBSCRIBE_INTERVAL);
+            if until_stopped(&mut stop, wait).await.is_none() {
+                return;
+            }
+        };
+    }
+}
+
+/// runs `fut` unless the `Feed` owning `stop` is dropped first
+async fn until_stopped<F: Future>(stop: &mut watch::Receiver<()>, fut: F) -> Option<F::Output> {
+    match future::select(pin!(fut), pin!(stop.changed())).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right(_) => None,
+    }
+}
+
+fn receiver_stream<T>(
+    chan: String,
+    rx: broadcast::Receiver<RespValue>,
+    lease: FeedLease,
+) -> Subscription<T>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    stream::unfold((rx, chan, lease), |(mut rx, chan, lease)| async move {
+        loop {
+            match rx.recv().await {
+                Ok(resp) => {
+                    let msg = decode_message::<T>(&chan, resp);
+                    return Some((msg, (rx, chan, lease)));
+                }
+                Err(RecvError::Lagged(n)) => {
+                    warn!("redis subscriber lagged chan={} skipped={}", chan, n);
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+    .boxed()
+}
+
+fn decode_message<T>(chan: &str, resp: RespValue) -> Result<T, SubscriptionDecodeError>
+where
+    T: DeserializeOwned,
+{
+    let payload = String::from_resp(resp.clone()).map_err(|e| SubscriptionDecodeError {
+        chan: chan.to_owned(),
+        payload: format!("{:?}", resp),
+        err: e.to_string(),
+    })?;
+
+    decode_payload(chan, payload)
+}
+
+pub(crate) fn decode_payload<T>(chan: &str, payload: String) -> Result<T, SubscriptionDecodeError>
+where
+    T: DeserializeOwned,
+{
+    serde_json::from_str::<T>(&payload).map_err(|e| SubscriptionDecodeError {
+        chan: chan.to_owned(),
+        payload,
+        err: e.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::types::PeerChanged;
+
+    #[test]
+    fn test_decode_message() {
+        // a peer published before ipv6 support
+        let payload = r#"{"Connected":{"uuid":"u","login_session_id":"s","ip_u32":16909060}}"#;
+        let msg = decode_message::<PeerChanged>("chan", RespValue::BulkString(payload.into()));
+        assert!(
+            matches!(msg, Ok(PeerChanged::Connected(info)) if info.ip.to_string() == "1.2.3.4")
+        );
+
+        let payload = r#"{"Connected":{"uuid":"u","login_session_id":"s","ip":"2001:db8::1"}}"#;
+        let msg = decode_message::<PeerChanged>("chan", RespValue::BulkString(payload.into()));
+        assert!(
+            matches!(msg, Ok(PeerChanged::Connected(info)) if info.ip.to_string() == "2001:db8::1")
+        );
+
+        let err = decode_message::<PeerChanged>("chan", RespValue::BulkString(b"{}".to_vec()))
+            .unwrap_err();
+        assert_eq!(err.chan, "chan");
+        assert_eq!(err.payload, "{}");
+
+        let err = decode_message::<PeerChanged>("chan", RespValue::Array(vec![])).unwrap_err();
+        assert_eq!(err.payload, "Array([])");
+    }
+
+    #[tokio::test]
+    async fn test_feed_fans_out() {
+        let lease = || FeedLease {
+            hub: Weak::new(),
+            chan: "chan".to_owned(),
+        };
+        let (tx, rx) = broadcast::channel(FEED_CAPACITY);
+        let mut first = receiver_stream::<u32>("chan".to_owned(), rx, lease());
+        let mut second = receiver_stream::<u32>("chan".to_owned(), tx.subscribe(), lease());
+        let source = stream::iter(["1", "2"].map(|m| Ok(RespValue::BulkString(m.into()))));
+        let (_stop, stop_rx) = watch::channel(());
+
+        // no hub left to resubscribe through, the feed stops once the source ends
+        feed(
+            Weak::new(),
+            "chan".to_owned(),
+            0,
+            source.boxed(),
+            tx,
+            stop_rx,
+        )
+        .await;
+        for sub in [&mut first, &mut second] {
+            assert_eq!(sub.next().await.unwrap().unwrap(), 1);
+            assert_eq!(sub.next().await.unwrap().unwrap(), 2);
+            assert!(sub.next().await.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_feed_stops_when_released() {
+        let (tx, _rx) = broadcast::channel(FEED_CAPACITY);
+        let (stop, stop_rx) = watch::channel(());
+        let task = tokio::spawn(feed(
+            Weak::new(),
+            "chan".to_owned(),
+            0,
+            stream::pending().boxed(),
+            tx,
+            stop_rx,
+        ));
+        // the source never ends, only dropping the feed stops the task
+        drop(stop);
+        tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
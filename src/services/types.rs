@@ -1,6 +1,9 @@
 use serde::{Deserialize, Serialize};
 
-use crate::types::connection::ProxyAccData;
+use crate::types::{
+    accounting::UserBalance,
+    connection::{PeerIp, ProxyAccData},
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum PeerChanged {
@@ -12,7 +15,9 @@ pub enum PeerChanged {
 pub struct PeerChangedInfo {
     pub uuid: String,
     pub login_session_id: String,
-    pub ip_u32: u32,
+    /// entries written before ipv6 support carry the ip as `ip_u32`
+    #[serde(alias = "ip_u32")]
+    pub ip: PeerIp,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -22,3 +27,19 @@ pub enum ProxyAccChanged {
     Deleted(String), // proxy_acc_id
     RefreshAll(),
 }
+
+/// a proxy acc change as published on the versioned proxy acc channel,
+/// versions increase by one for every change
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersionedProxyAccChanged {
+    pub version: u64,
+    pub change: ProxyAccChanged,
+}
+
+#[derive(Debug, Clone)]
+pub enum DebitOutcome {
+    /// the amount has been subtracted, holds the new balance
+    Debited(UserBalance),
+    /// the balance does not cover the amount and is left untouched
+    Insufficient(UserBalance),
+}
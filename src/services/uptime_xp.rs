@@ -0,0 +1,349 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use log::warn;
+use redis::AsyncCommands as _;
+use serde::{Deserialize, Serialize};
+use web3::types::Address;
+
+use crate::{
+    types::{connection::MAX_INACTIVE_TIME, user_online_point::UserOnlinePoint, user_xp::UserXp},
+    utils::address_to_string,
+};
+
+use super::redis::{DPNRedisKey, RedisService};
+
+/// how far in the future a poll may be before it is logged as skewed,
+/// polls are always clamped to the local clock
+pub const MAX_CLOCK_SKEW: i64 = 30;
+/// credited windows are remembered this long, a poll delivered later than this
+/// is only credited for the part inside it
+pub const CREDIT_RETENTION: i64 = 4 * MAX_INACTIVE_TIME;
+/// users of the pending hash flushed per script call
+const FLUSH_PAGE_SIZE: usize = 500;
+/// tries of an accumulation losing the race against another poll of the user
+const MAX_ACCUMULATE_TRIES: usize = 5;
+
+/// windows already credited to a user within `CREDIT_RETENTION`,
+/// sorted and without overlaps
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CreditedWindows {
+    pub windows: Vec<(u64, u64)>,
+    /// bumped on every write so concurrent polls of the user don't overwrite each other
+    pub version: u64,
+}
+
+/// turns `UserOnlinePoint` polls into uptime credited to the `uptime_xp` hash
+///
+/// a user is credited once per second of wall clock time no matter how many
+/// login sessions or masternodes report the user online, so only the part of a poll
+/// window not covered by the credited windows of the user counts, whatever order
+/// the polls arrive in. users credited since the last flush are marked pending so
+/// `flush` only snapshots those
+#[derive(Debug)]
+pub struct UptimeXpAccumulator {
+    redis_service: Arc<RedisService>,
+}
+
+impl UptimeXpAccumulator {
+    pub fn new(redis_service: Arc<RedisService>) -> Self {
+        Self { redis_service }
+    }
+
+    /// credits the window of the poll to its user and returns the credited seconds
+    pub async fn accumulate(self: Arc<Self>, point: UserOnlinePoint) -> Result<u64> {
+        let user_addr = point
+            .user_addr
+            .parse::<Address>()
+            .map_err(|e| anyhow!("invalid user_addr={} err={}", point.user_addr, e))?;
+        let now = Utc::now().timestamp();
+        let (from, to) = match credit_window(&point, now) {
+            Some(window) => window,
+            None => return Ok(0),
+        };
+
+        let user_addr = address_to_string(user_addr);
+        let credited_k = DPNRedisKey::get_uptime_xp_credited_k(user_addr.clone());
+        let (uptime_xp_k, f) = DPNRedisKey::get_uptime_xp_kf(user_addr.clone());
+        let (created_k, _) = DPNRedisKey::get_uptime_xp_created_kf(user_addr.clone());
+        let (pending_k, _) = DPNRedisKey::get_uptime_xp_pending_kf(user_addr.clone());
+        let mut conn = self.redis_service.clone().get_async_conn();
+        for _ in 0..MAX_ACCUMULATE_TRIES {
+            let current: Option<String> = conn
+                .get(self.redis_service.namespaced(credited_k.clone()))
+                .await
+                .map_err(|e| {
+                    anyhow!(
+                        "redis get credited uptime failed user_addr={} err={}",
+                        user_addr,
+                        e
+                    )
+                })?;
+            let current = match current {
+                Some(obj_str) => serde_json::from_str::<CreditedWindows>(&obj_str)
+                    .map_err(|e| anyhow!("decode credited uptime failed err={}", e))?,
+                None => CreditedWindows::default(),
+            };
+            let horizon = (now - CREDIT_RETENTION).max(0) as u64;
+            let (seconds, windows) = credit(&current.windows, from, to, horizon);
+            if seconds == 0 {
+                return Ok(0);
+            }
+            let next = CreditedWindows {
+                windows,
+                version: current.version + 1,
+            };
+            let updated: i64 = redis::Script::new(ACCUMULATE_SCRIPT)
+                .key(self.redis_service.namespaced(credited_k.clone()))
+                .key(self.redis_service.namespaced(uptime_xp_k.clone()))
+                .key(self.redis_service.namespaced(created_k.clone()))
+                .key(self.redis_service.namespaced(pending_k.clone()))
+                .arg(current.version)
+                .arg(serde_json::to_string(&next).unwrap())
+                .arg(CREDIT_RETENTION * 1000)
+                .arg(f.clone())
+                .arg(seconds)
+                .arg(now)
+                .invoke_async(&mut conn)
+                .await
+                .map_err(|e| {
+                    anyhow!(
+                        "redis accumulate uptime failed user_addr={} err={}",
+                        user_addr,
+                        e
+                    )
+                })?;
+            if updated == 1 {
+                return Ok(seconds);
+            }
+        }
+        Err(anyhow!(
+            "accumulate uptime kept conflicting user_addr={}",
+            user_addr
+        ))
+    }
+
+    /// returns the total uptime of every user credited since the last flush and clears
+    /// their pending mark, `minutes_uptime` is the absolute uptime of the user. the
+    /// pending hash is walked with HSCAN and flushed a page at a time
+    pub async fn flush(self: Arc<Self>) -> Result<Vec<UserXp>> {
+        let ns = |k: String| self.redis_service.namespaced(k);
+        let k = ns(DPNRedisKey::get_uptime_xp_pending_kf("".to_owned()).0);
+        let uptime_xp_k = ns(DPNRedisKey::get_uptime_xp_kf("".to_owned()).0);
+        let created_k = ns(DPNRedisKey::get_uptime_xp_created_kf("".to_owned()).0);
+        let mut scan_conn = self.redis_service.clone().get_async_conn();
+        let mut conn = self.redis_service.clone().get_async_conn();
+        let mut iter = scan_conn
+            .hscan::<_, (String, String)>(k.clone())
+            .await
+            .map_err(|e| anyhow!("redis scan uptime failed err={}", e))?;
+
+        let mut rs: Vec<UserXp> = vec![];
+        let mut scanned = false;
+        while !scanned {
+            let mut page: Vec<String> = vec![];
+            while page.len() < FLUSH_PAGE_SIZE {
+                match iter.next_item().await {
+                    Some((field, _)) => page.push(field),
+                    None => {
+                        scanned = true;
+                        break;
+                    }
+                }
+            }
+            if page.is_empty() {
+                break;
+            }
+            let flushed: Vec<(String, u64, i64, i64)> = redis::Script::new(FLUSH_SCRIPT)
+                .key(k.clone())
+                .key(uptime_xp_k.clone())
+                .key(created_k.clone())
+                .arg(page)
+                .invoke_async(&mut conn)
+                .await
+                .map_err(|e| anyhow!("redis flush uptime failed err={}", e))?;
+            rs.extend(flushed.into_iter().filter_map(
+                |(user_addr, seconds, created_at, updated_at)| {
+                    user_xp(user_addr, seconds, created_at, updated_at)
+                },
+            ));
+        }
+        Ok(rs)
+    }
+}
+
+/// builds the snapshot of a flushed user, invalid addrs are logged and dropped
+fn user_xp(user_addr: String, seconds: u64, created_at: i64, updated_at: i64) -> Option<UserXp> {
+    let user_addr = match user_addr.parse::<Address>() {
+        Ok(user_addr) => user_addr,
+        Err(e) => {
+            warn!(
+                "uptime xp dropped invalid user_addr={} err={}",
+                user_addr, e
+            );
+            return None;
+        }
+    };
+    Some(UserXp::new(
+        user_addr,
+        seconds as f64 / 60.0,
+        created_at,
+        updated_at,
+    ))
+}
+
+/// the window of a poll that may be credited, clamped to `now`. a window longer
+/// than `MAX_INACTIVE_TIME` means the user went offline in between so only
+/// the poll itself counts, nothing is credited for it
+fn credit_window(point: &UserOnlinePoint, now: i64) -> Option<(u64, u64)> {
+    let now = now.max(0) as u64;
+    let mut to = point.poll_at;
+    if to > now + MAX_CLOCK_SKEW as u64 {
+        warn!(
+            "uptime poll from the future user_addr={} masternode_id={} poll_at={} now={}",
+            point.user_addr, point.masternode_id, point.poll_at, now
+        );
+    }
+    to = to.min(now);
+
+    let from = point.last_poll_at;
+    if from >= to || to - from > MAX_INACTIVE_TIME as u64 {
+        return None;
+    }
+    Some((from, to))
+}
+
+/// the seconds of `from..to` not in `credited` and the credited windows with it merged in.
+/// windows ending before `horizon` are forgotten and nothing before it is credited
+fn credit(credited: &[(u64, u64)], from: u64, to: u64, horizon: u64) -> (u64, Vec<(u64, u64)>) {
+    let from = from.max(horizon);
+    let mut windows: Vec<(u64, u64)> = credited
+        .iter()
+        .copied()
+        .filter(|(_, end)| *end > horizon)
+        .collect();
+    if from >= to {
+        return (0, windows);
+    }
+
+    let covered: u64 = windows
+        .iter()
+        .map(|(start, end)| (*end).min(to).saturating_sub((*start).max(from)))
+        .sum();
+    let (mut start, mut end) = (from, to);
+    windows.retain(|(s, e)| {
+        let touches = *s <= end && *e >= start;
+        if touches {
+            start = start.min(*s);
+            end = end.max(*e);
+        }
+        !touches
+    });
+    windows.push((start, end));
+    windows.sort();
+    (to - from - covered, windows)
+}
+
+/// writes the credited windows of a user if nobody wrote them since they were read,
+/// adds the credited seconds to the uptime of the user and marks the user pending
+/// returns 1 when written, 0 when the version moved on
+///
+/// KEYS[1] credited windows, KEYS[2] uptime xp hash, KEYS[3] uptime xp created hash,
+/// KEYS[4] uptime xp pending hash
+/// ARGV[1] version the windows were read at, 0 when there were none
+/// ARGV[2] new windows, ARGV[3] ttl of the windows in ms
+/// ARGV[4] user addr, ARGV[5] credited seconds, ARGV[6] now
+const ACCUMULATE_SCRIPT: &str = r#"
+local current = redis.call('GET', KEYS[1])
+local version = 0
+if current then
+    version = cjson.decode(current)['version']
+end
+if version ~= tonumber(ARGV[1]) then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[2], 'PX', ARGV[3])
+redis.call('HINCRBY', KEYS[2], ARGV[4], ARGV[5])
+redis.call('HSETNX', KEYS[3], ARGV[4], ARGV[6])
+redis.call('HSET', KEYS[4], ARGV[4], ARGV[6])
+return 1
+"#;
+
+/// KEYS[1] uptime xp pending hash, KEYS[2] uptime xp hash, KEYS[3] uptime xp created hash
+/// ARGV user addrs of one page
+/// returns {user addr, seconds, created at, updated at} of the pending users of the page
+/// and clears their pending mark
+const FLUSH_SCRIPT: &str = r#"
+local flushed = {}
+for _, field in ipairs(ARGV) do
+    local updated_at = redis.call('HGET', KEYS[1], field)
+    if updated_at then
+        local seconds = redis.call('HGET', KEYS[2], field) or '0'
+        local created_at = redis.call('HGET', KEYS[3], field) or updated_at
+        table.insert(flushed, {field, seconds, created_at, updated_at})
+        redis.call('HDEL', KEYS[1], field)
+    end
+end
+return flushed
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(last_poll_at: u64, poll_at: u64) -> UserOnlinePoint {
+        UserOnlinePoint::new(
+            "0x0000000000000000000000000000000000000001".to_owned(),
+            "session".to_owned(),
+            "ms".to_owned(),
+            poll_at,
+            last_poll_at,
+        )
+    }
+
+    #[test]
+    fn test_credit_window() {
+        let now = 10_000;
+        assert_eq!(
+            credit_window(&point(9_940, 10_000), now),
+            Some((9_940, 10_000))
+        );
+        // skewed clock, clamped to now
+        assert_eq!(
+            credit_window(&point(9_940, 10_500), now),
+            Some((9_940, 10_000))
+        );
+        // gap longer than the inactive time
+        assert_eq!(credit_window(&point(9_000, 10_000), now), None);
+        // reversed or empty window
+        assert_eq!(credit_window(&point(10_000, 9_940), now), None);
+        assert_eq!(credit_window(&point(10_000, 10_000), now), None);
+    }
+
+    #[test]
+    fn test_credit_out_of_order() {
+        // two sessions polling the same minute, the second one earns nothing
+        let (seconds, windows) = credit(&[], 100, 160, 0);
+        assert_eq!((seconds, windows.clone()), (60, vec![(100, 160)]));
+        let (seconds, windows) = credit(&windows, 100, 160, 0);
+        assert_eq!(seconds, 0);
+
+        // a later window arrives before an earlier one, both are credited
+        let (seconds, windows) = credit(&windows, 220, 280, 0);
+        assert_eq!(
+            (seconds, windows.clone()),
+            (60, vec![(100, 160), (220, 280)])
+        );
+        let (seconds, windows) = credit(&windows, 160, 220, 0);
+        assert_eq!((seconds, windows.clone()), (60, vec![(100, 280)]));
+
+        // a window overlapping the credited ones only earns the gaps
+        let (seconds, windows) = credit(&[(100, 160), (220, 280)], 130, 250, 0);
+        assert_eq!((seconds, windows), (60, vec![(100, 280)]));
+
+        // nothing before the horizon is credited and old windows are forgotten
+        let (seconds, windows) = credit(&[(100, 160), (220, 280)], 140, 200, 170);
+        assert_eq!((seconds, windows), (30, vec![(170, 200), (220, 280)]));
+    }
+}
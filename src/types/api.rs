@@ -30,6 +30,10 @@ impl ErrorWrapper {
         self.err_msg.clone()
     }
 
+    pub fn status_code(&self) -> u16 {
+        self.status_code
+    }
+
     pub fn build(&mut self) -> HttpResponse {
         match StatusCode::from_u16(self.status_code) {
             Ok(code) => match code {
@@ -38,9 +42,13 @@ impl ErrorWrapper {
                 StatusCode::BAD_REQUEST => return HttpResponse::BadRequest().json(self),
                 StatusCode::UNAUTHORIZED => return HttpResponse::Unauthorized().json(self),
                 StatusCode::NOT_FOUND => return HttpResponse::NotFound().json(self),
+                StatusCode::CONFLICT => HttpResponse::Conflict().json(self),
                 StatusCode::INTERNAL_SERVER_ERROR => {
                     return HttpResponse::InternalServerError().json(self)
                 }
+                StatusCode::TOO_MANY_REQUESTS => HttpResponse::TooManyRequests().json(self),
+                StatusCode::BAD_GATEWAY => HttpResponse::BadGateway().json(self),
+                StatusCode::SERVICE_UNAVAILABLE => HttpResponse::ServiceUnavailable().json(self),
                 _ => {
                     return HttpResponse::InternalServerError().json(Self {
                         status_code: self.status_code,
@@ -1,10 +1,18 @@
+use std::{
+    fmt::Display,
+    net::{AddrParseError, IpAddr, Ipv4Addr},
+    str::FromStr,
+};
+
 use dpn_proto::proxy_acc::ProtoProxyAcc;
 use num_derive::FromPrimitive;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use utoipa::ToSchema;
 
 use crate::utils::{bytes_to_hex_string, hash::hash};
 
+use super::geo::Network;
+
 pub const DEFAULT_IP_ROTATION_PERIOD: i64 = 300;
 pub const MAX_INACTIVE_TIME: i64 = 300; // 300 seconds
 
@@ -14,15 +22,100 @@ pub struct UserConnectStats {
     pub last_connect_time: i64,
 }
 
+/// ip of a peer of either family, used as the peer id in the peers hash and the peer queue
+///
+/// written in its textual form like `1.2.3.4` or `2001:db8::1`. ipv4 peers written
+/// before ipv6 was supported used the ip as a `u32`, that form is still read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PeerIp(pub IpAddr);
+
+impl PeerIp {
+    /// whether `s` is the `u32` form ipv4 peers were written in before
+    pub fn is_legacy(s: &str) -> bool {
+        s.parse::<u32>().is_ok()
+    }
+}
+
+impl From<IpAddr> for PeerIp {
+    fn from(ip: IpAddr) -> Self {
+        Self(ip)
+    }
+}
+
+impl From<u32> for PeerIp {
+    fn from(ip_u32: u32) -> Self {
+        Self(IpAddr::V4(Ipv4Addr::from(ip_u32)))
+    }
+}
+
+impl Display for PeerIp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for PeerIp {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<u32>() {
+            Ok(ip_u32) => Ok(Self::from(ip_u32)),
+            Err(_) => s.parse::<IpAddr>().map(Self),
+        }
+    }
+}
+
+impl Serialize for PeerIp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PeerIp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Legacy(u32),
+            Text(String),
+        }
+        match Repr::deserialize(deserializer)? {
+            Repr::Legacy(ip_u32) => Ok(Self::from(ip_u32)),
+            Repr::Text(s) => s.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct PeernodeInfo {
     pub peer_id: String,
+    /// textual ip of the peer of either family, see `PeerIp`
     pub ip_addr: String,
     pub throughput: f64,
     pub rate_per_kb: u64,
     pub rate_per_second: u64,
     pub city_geoname_id: u32,
     pub country_geoname_id: u32,
+    /// asn and connection type of the peer ip, when the geo service has the databases
+    #[serde(default)]
+    pub network: Option<Network>,
+}
+
+#[cfg(test)]
+impl PeernodeInfo {
+    /// free peer without geo for tests, set other fields with struct update syntax
+    pub fn fixture(peer_id: &str, ip_addr: &str) -> Self {
+        Self {
+            peer_id: peer_id.to_owned(),
+            ip_addr: ip_addr.to_owned(),
+            throughput: 0.0,
+            rate_per_kb: 0,
+            rate_per_second: 0,
+            city_geoname_id: 0,
+            country_geoname_id: 0,
+            network: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -98,6 +191,28 @@ impl ProxyAccData {
     }
 }
 
+#[cfg(test)]
+impl ProxyAccData {
+    /// proxy acc for tests with the password `{id}_password`, without geo nor rates.
+    /// set other fields with struct update syntax
+    pub fn fixture(id: &str) -> Self {
+        Self {
+            id: id.to_owned(),
+            password: format!("{}_password", id),
+            ip_rotation_period: 0,
+            whitelisted_ip: None,
+            user_addr: "0x0".to_owned(),
+            country_geoname_id: 0,
+            city_geoname_id: None,
+            rate_per_kb: 0,
+            rate_per_second: 0,
+            prioritized_ip: None,
+            prioritized_ip_level: None,
+            created_at: 0,
+        }
+    }
+}
+
 impl Into<ProtoProxyAcc> for ProxyAccData {
     fn into(self) -> ProtoProxyAcc {
         ProtoProxyAcc {
@@ -1,93 +1,316 @@
-use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Deserializer, Serialize};
 use utoipa::ToSchema;
 
 pub const DEFAULT_CONTINENTAL_CODE: &str = "DEFAULT";
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+/// locale of the `name` fields when no preferred locale is given or known
+pub const DEFAULT_LOCALE: &str = "en";
+
+/// values `Default` used to fill in for unknown places, payloads written back
+/// then still carry them and they are read as unknown
+const PLACEHOLDERS: [&str; 6] = [
+    "Default code",
+    "Default continent name",
+    "Default iso_code",
+    "Default country name",
+    "Default city",
+    "Default timezone",
+];
+
+/// where an ip is, every part is `None` when it is unknown and
+/// `Geo::default()` knows nothing
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
 pub struct Geo {
+    #[serde(default, deserialize_with = "known")]
     pub city: Option<City>,
+    #[serde(default, deserialize_with = "known")]
     pub continent: Option<Continent>,
+    #[serde(default, deserialize_with = "known")]
     pub country: Option<Country>,
+    #[serde(default, deserialize_with = "known")]
     pub location: Option<Location>,
+    #[serde(default)]
+    pub network: Option<Network>,
+    /// states, provinces and the like, from the largest to the smallest
+    #[serde(default)]
+    pub subdivisions: Vec<Subdivision>,
+    #[serde(default)]
+    pub postal_code: Option<String>,
+    /// country the ip block is registered in by the isp, may differ from `country`
+    #[serde(default, deserialize_with = "known")]
+    pub registered_country: Option<Country>,
+    /// country represented by users of the ip, like a military base abroad
+    #[serde(default, deserialize_with = "known")]
+    pub represented_country: Option<Country>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
 pub struct Continent {
+    #[serde(default, deserialize_with = "non_placeholder")]
     pub code: Option<String>,
+    #[serde(default, deserialize_with = "non_zero_id")]
     pub geoname_id: Option<u32>,
+    #[serde(default, deserialize_with = "non_placeholder")]
     pub name: Option<String>,
+    /// names by locale
+    #[serde(default)]
+    pub names: BTreeMap<String, String>,
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug, ToSchema)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default, ToSchema)]
 pub struct Country {
+    #[serde(default, deserialize_with = "non_zero_id")]
     pub geoname_id: Option<u32>,
     pub is_in_european_union: Option<bool>,
+    #[serde(default, deserialize_with = "non_placeholder")]
     pub iso_code: Option<String>,
+    #[serde(default, deserialize_with = "non_placeholder")]
     pub name: Option<String>,
+    /// names by locale
+    #[serde(default)]
+    pub names: BTreeMap<String, String>,
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug, ToSchema)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default, ToSchema)]
 pub struct City {
+    #[serde(default, deserialize_with = "non_zero_id")]
     pub geoname_id: Option<u32>,
+    #[serde(default, deserialize_with = "non_placeholder")]
     pub name: Option<String>,
+    /// names by locale
+    #[serde(default)]
+    pub names: BTreeMap<String, String>,
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, ToSchema)]
+pub struct Subdivision {
+    pub geoname_id: Option<u32>,
+    pub iso_code: Option<String>,
+    pub name: Option<String>,
+    /// names by locale
+    #[serde(default)]
+    pub names: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
 pub struct Location {
+    /// radius in km around the coordinates the ip is likely in
+    #[serde(default)]
+    pub accuracy_radius: Option<u16>,
     pub latitude: Option<f64>,
     pub longitude: Option<f64>,
+    #[serde(default, deserialize_with = "non_zero_id")]
     pub metro_code: Option<u16>,
+    #[serde(default, deserialize_with = "non_placeholder")]
     pub time_zone: Option<String>,
 }
 
-impl Default for Geo {
-    fn default() -> Self {
-        Self {
-            city: Some(City::default()),
-            continent: Some(Continent::default()),
-            country: Some(Country::default()),
-            location: Some(Location::default()),
+/// owner and kind of the network of an ip, read from the asn, isp and connection type databases
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct Network {
+    /// autonomous system number
+    pub asn: Option<u32>,
+    pub as_organization: Option<String>,
+    pub isp: Option<String>,
+    pub organization: Option<String>,
+    pub connection_type: Option<ConnectionType>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum ConnectionType {
+    Dialup,
+    CableDsl,
+    Cellular,
+    /// corporate networks and datacenters
+    Corporate,
+    Satellite,
+}
+
+impl ConnectionType {
+    /// parses the `connection_type` of a GeoIP2 Connection-Type record
+    pub fn from_mmdb(connection_type: &str) -> Option<Self> {
+        match connection_type {
+            "Dialup" => Some(Self::Dialup),
+            "Cable/DSL" => Some(Self::CableDsl),
+            "Cellular" => Some(Self::Cellular),
+            "Corporate" => Some(Self::Corporate),
+            "Satellite" => Some(Self::Satellite),
+            _ => None,
         }
     }
+
+    pub fn is_residential(&self) -> bool {
+        matches!(self, Self::Dialup | Self::CableDsl)
+    }
+
+    pub fn is_mobile(&self) -> bool {
+        matches!(self, Self::Cellular)
+    }
 }
 
-impl Default for Continent {
-    fn default() -> Self {
-        Self {
-            code: Some("Default code".to_string()),
-            geoname_id: Some(0),
-            name: Some("Default continent name".to_string()),
-        }
+/// flags of an ip listed in the anonymous ip database, an unlisted ip has none set
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct AnonymousIp {
+    pub is_anonymous: bool,
+    pub is_anonymous_vpn: bool,
+    pub is_hosting_provider: bool,
+    pub is_public_proxy: bool,
+    pub is_residential_proxy: bool,
+    pub is_tor_exit_node: bool,
+}
+
+/// the name of the first of the locales that has one, falling back to `DEFAULT_LOCALE`
+pub fn localized_name(names: &BTreeMap<String, String>, locales: &[String]) -> Option<String> {
+    locales
+        .iter()
+        .map(|l| l.as_str())
+        .chain([DEFAULT_LOCALE])
+        .find_map(|l| names.get(l))
+        .cloned()
+}
+
+impl Geo {
+    /// whether anything is known about where the ip is
+    pub fn is_known(&self) -> bool {
+        self.country.as_ref().is_some_and(|c| c.is_known())
+            || self.city.as_ref().is_some_and(|c| c.is_known())
+            || self.location.as_ref().is_some_and(|l| l.is_known())
     }
 }
 
-impl Default for Country {
-    fn default() -> Self {
-        Self {
-            geoname_id: Some(0),
-            is_in_european_union: Some(false),
-            iso_code: Some("Default iso_code".to_string()),
-            name: Some("Default country name".to_string()),
-        }
+impl Continent {
+    pub fn is_known(&self) -> bool {
+        self.geoname_id.is_some() || self.code.is_some()
     }
 }
 
-impl Default for City {
-    fn default() -> Self {
-        Self {
-            geoname_id: Some(0),
-            name: Some("Default city".to_string()),
-        }
+impl Country {
+    pub fn is_known(&self) -> bool {
+        self.geoname_id.is_some() || self.iso_code.is_some()
+    }
+}
+
+impl City {
+    pub fn is_known(&self) -> bool {
+        self.geoname_id.is_some() || self.name.is_some()
     }
 }
 
-impl Default for Location {
-    fn default() -> Self {
-        Self {
-            latitude: Some(0.0),
-            longitude: Some(0.0),
-            metro_code: Some(0),
-            time_zone: Some("Default timezone".to_string()),
+impl Location {
+    /// whether there are coordinates, `0,0` is the old placeholder and not a place
+    pub fn is_known(&self) -> bool {
+        match (self.latitude, self.longitude) {
+            (Some(latitude), Some(longitude)) => latitude != 0.0 || longitude != 0.0,
+            _ => false,
         }
     }
 }
+
+trait Known {
+    fn is_known(&self) -> bool;
+}
+
+impl Known for Continent {
+    fn is_known(&self) -> bool {
+        Continent::is_known(self)
+    }
+}
+
+impl Known for Country {
+    fn is_known(&self) -> bool {
+        Country::is_known(self)
+    }
+}
+
+impl Known for City {
+    fn is_known(&self) -> bool {
+        City::is_known(self)
+    }
+}
+
+impl Known for Location {
+    fn is_known(&self) -> bool {
+        Location::is_known(self)
+    }
+}
+
+/// reads a part of `Geo`, a part that knows nothing is `None`
+fn known<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Known,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.filter(|t| t.is_known()))
+}
+
+fn non_placeholder<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(
+        Option::<String>::deserialize(deserializer)?
+            .filter(|s| !PLACEHOLDERS.contains(&s.as_str())),
+    )
+}
+
+/// geoname ids and metro codes are never zero, zero is the old placeholder
+fn non_zero_id<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default + PartialEq,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.filter(|id| *id != T::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_localized_name() {
+        let names = BTreeMap::from([
+            ("en".to_owned(), "Munich".to_owned()),
+            ("de".to_owned(), "München".to_owned()),
+        ]);
+        let locales = |l: &[&str]| l.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            localized_name(&names, &locales(&["vi", "de"])),
+            Some("München".to_owned())
+        );
+        assert_eq!(
+            localized_name(&names, &locales(&["vi"])),
+            Some("Munich".to_owned())
+        );
+        assert_eq!(localized_name(&BTreeMap::new(), &locales(&["de"])), None);
+
+        // payloads from before localized names still read
+        let city: City = serde_json::from_str(r#"{"geoname_id":1,"name":"Munich"}"#).unwrap();
+        assert!(city.names.is_empty());
+    }
+
+    #[test]
+    fn test_placeholders_read_as_unknown() {
+        let old = r#"{
+            "city": {"geoname_id": 0, "name": "Default city"},
+            "continent": {"code": "Default code", "geoname_id": 0, "name": "Default continent name"},
+            "country": {"geoname_id": 0, "is_in_european_union": false, "iso_code": "Default iso_code", "name": "Default country name"},
+            "location": {"latitude": 0.0, "longitude": 0.0, "metro_code": 0, "time_zone": "Default timezone"}
+        }"#;
+        let geo: Geo = serde_json::from_str(old).unwrap();
+        assert!(!geo.is_known());
+        assert!(geo.city.is_none() && geo.country.is_none() && geo.location.is_none());
+        assert!(!Geo::default().is_known());
+
+        let known = r#"{
+            "city": {"geoname_id": 2867714, "name": "Munich"},
+            "continent": null,
+            "country": {"geoname_id": 2921044, "is_in_european_union": true, "iso_code": "DE", "name": "Germany"},
+            "location": {"latitude": 48.1, "longitude": 11.6, "metro_code": null, "time_zone": "Europe/Berlin"}
+        }"#;
+        let geo: Geo = serde_json::from_str(known).unwrap();
+        assert!(geo.is_known());
+        assert_eq!(geo.country.unwrap().iso_code, Some("DE".to_owned()));
+    }
+}